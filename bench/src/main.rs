@@ -0,0 +1,788 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{
+        atomic::{AtomicU32, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{format_err, Result};
+use base64::{engine::general_purpose, Engine as _};
+use futures_util::{SinkExt, StreamExt};
+use goval::command::Body;
+use prost::Message;
+use rand::Rng;
+use tokio::sync::{mpsc, oneshot};
+use tokio_tungstenite::tungstenite::Message as WsMessage;
+use tracing::{debug, error, info, warn};
+
+static USAGE: &str = "Usage: homeval-bench [options]
+
+Options:
+    --addr <host:port>     Address of the homeval goval server (default: 127.0.0.1:8080)
+    --sessions <n>         Number of concurrent sessions to open (default: 10)
+    --duration <secs>      How long to generate traffic for (default: 30)
+    --ramp <ms>            Delay between opening each session (default: 50)
+    --file <path>          File the sessions collaborate on (default: homeval-bench.txt)
+    --edit-rate <hz>       OT edits per session per second (default: 5)
+    --cursor-rate <hz>     Cursor updates per session per second (default: 2)
+    --typing-rate <hz>     Chat typing indicators per session per second (default: 1)
+    --chat-rate <hz>       Chat messages per session per second (default: 0.2)
+    --input-rate <hz>      Shell inputs per session per second (default: 0.5)
+    --ping-rate <hz>       Pings per session per second (default: 1)
+
+A rate of 0 disables that kind of traffic.";
+
+// Marker embedded in broadcast payloads so receivers can work out fan-out delay.
+static MARKER: &str = "hbench:";
+
+struct Options {
+    addr: String,
+    sessions: usize,
+    duration: Duration,
+    ramp: Duration,
+    file: String,
+    edit_rate: f64,
+    cursor_rate: f64,
+    typing_rate: f64,
+    chat_rate: f64,
+    input_rate: f64,
+    ping_rate: f64,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            addr: "127.0.0.1:8080".to_string(),
+            sessions: 10,
+            duration: Duration::from_secs(30),
+            ramp: Duration::from_millis(50),
+            file: "homeval-bench.txt".to_string(),
+            edit_rate: 5.0,
+            cursor_rate: 2.0,
+            typing_rate: 1.0,
+            chat_rate: 0.2,
+            input_rate: 0.5,
+            ping_rate: 1.0,
+        }
+    }
+}
+
+impl Options {
+    fn parse() -> Result<Options> {
+        let mut options = Options::default();
+        let mut args = std::env::args().skip(1);
+
+        while let Some(arg) = args.next() {
+            if arg == "-h" || arg == "--help" {
+                println!("{}", USAGE);
+                std::process::exit(0);
+            }
+
+            let value = args
+                .next()
+                .ok_or_else(|| format_err!("Missing value for argument: {}", arg))?;
+
+            match arg.as_str() {
+                "--addr" => options.addr = value,
+                "--sessions" => options.sessions = value.parse()?,
+                "--duration" => options.duration = Duration::from_secs_f64(value.parse()?),
+                "--ramp" => options.ramp = Duration::from_millis(value.parse()?),
+                "--file" => options.file = value,
+                "--edit-rate" => options.edit_rate = value.parse()?,
+                "--cursor-rate" => options.cursor_rate = value.parse()?,
+                "--typing-rate" => options.typing_rate = value.parse()?,
+                "--chat-rate" => options.chat_rate = value.parse()?,
+                "--input-rate" => options.input_rate = value.parse()?,
+                "--ping-rate" => options.ping_rate = value.parse()?,
+                _ => return Err(format_err!("Unknown argument: {}\n\n{}", arg, USAGE)),
+            }
+        }
+
+        if options.sessions == 0 {
+            return Err(format_err!("--sessions must be at least 1"));
+        }
+
+        Ok(options)
+    }
+}
+
+#[derive(Default)]
+struct Stats {
+    replies: HashMap<&'static str, Vec<Duration>>,
+    fanout: HashMap<&'static str, Vec<Duration>>,
+    sent: usize,
+    received: usize,
+    errors: usize,
+}
+
+impl Stats {
+    fn merge(&mut self, other: Stats) {
+        for (kind, mut samples) in other.replies {
+            self.replies.entry(kind).or_default().append(&mut samples);
+        }
+        for (kind, mut samples) in other.fanout {
+            self.fanout.entry(kind).or_default().append(&mut samples);
+        }
+        self.sent += other.sent;
+        self.received += other.received;
+        self.errors += other.errors;
+    }
+}
+
+// Edits older than this many newer ones are forgotten, broadcasts that slow
+// aren't counted
+const TRACKED_EDITS: usize = 10_000;
+
+// When OT edits and cursors were sent, shared by every session so receivers can
+// work out fan-out delay. Edits are told apart by their nonce, which the server
+// passes along, cursors by their id and position. Each session only has the
+// one cursor, so only its latest position is kept.
+#[derive(Default)]
+struct Sent {
+    edits: Mutex<BTreeMap<u32, u128>>,
+    cursors: Mutex<HashMap<String, (u32, u128)>>,
+    next_nonce: AtomicU32,
+}
+
+struct Pending {
+    kind: &'static str,
+    sent: Instant,
+    waiter: Option<oneshot::Sender<goval::Command>>,
+}
+
+#[derive(Default)]
+struct Document {
+    version: u32,
+    len: u32,
+}
+
+#[derive(Default)]
+struct Channels {
+    ot: i32,
+    chat: i32,
+    shell: i32,
+}
+
+struct Client {
+    index: usize,
+    user_id: u32,
+    username: String,
+    outbound: mpsc::UnboundedSender<WsMessage>,
+    pending: Mutex<HashMap<String, Pending>>,
+    stats: Mutex<Stats>,
+    document: Mutex<Document>,
+    channels: Mutex<Channels>,
+    ot_status: Mutex<Option<oneshot::Sender<goval::OtStatus>>>,
+    shell_tail: Mutex<String>,
+    next_ref: AtomicU64,
+    sent: Arc<Sent>,
+}
+
+impl Client {
+    async fn connect(options: &Options, index: usize, sent: Arc<Sent>) -> Result<Arc<Client>> {
+        let user_id = 1_000_000 + index as u32;
+        let username = format!("bench-{}", index);
+        let url = format!(
+            "ws://{}/wsv2/{}",
+            options.addr,
+            mint_token(user_id, &username)?
+        );
+
+        let (stream, _) = tokio_tungstenite::connect_async(url).await?;
+        let (mut write, mut read) = stream.split();
+        let (outbound, mut outbound_recv) = mpsc::unbounded_channel::<WsMessage>();
+
+        let client = Arc::new(Client {
+            index,
+            user_id,
+            username,
+            outbound,
+            pending: Mutex::new(HashMap::new()),
+            stats: Mutex::new(Stats::default()),
+            document: Mutex::new(Document::default()),
+            channels: Mutex::new(Channels::default()),
+            ot_status: Mutex::new(None),
+            shell_tail: Mutex::new(String::new()),
+            next_ref: AtomicU64::new(0),
+            sent,
+        });
+
+        tokio::spawn(async move {
+            while let Some(message) = outbound_recv.recv().await {
+                let closing = matches!(message, WsMessage::Close(_));
+                if let Err(err) = write.send(message).await {
+                    error!(%err, index, "Error sending message to server");
+                    break;
+                }
+
+                if closing {
+                    break;
+                }
+            }
+        });
+
+        let reader = client.clone();
+        tokio::spawn(async move {
+            while let Some(message) = read.next().await {
+                match message {
+                    Ok(WsMessage::Binary(buf)) => match goval::Command::decode(buf.as_slice()) {
+                        Ok(cmd) => reader.handle(cmd),
+                        Err(err) => error!(%err, index, "Error decoding message from server"),
+                    },
+                    Ok(WsMessage::Close(_)) => break,
+                    Ok(_) => {}
+                    Err(err) => {
+                        debug!(%err, index, "Websocket closed");
+                        break;
+                    }
+                }
+            }
+        });
+
+        Ok(client)
+    }
+
+    fn handle(&self, cmd: goval::Command) {
+        let received = Instant::now();
+        let mut stats = self.stats.lock().unwrap();
+        stats.received += 1;
+
+        if !cmd.r#ref.is_empty() {
+            if let Some(pending) = self.pending.lock().unwrap().remove(&cmd.r#ref) {
+                stats
+                    .replies
+                    .entry(pending.kind)
+                    .or_default()
+                    .push(received - pending.sent);
+
+                if let Some(waiter) = pending.waiter {
+                    let _ = waiter.send(cmd.clone());
+                }
+            }
+        }
+
+        match cmd.body {
+            Some(Body::Ot(packet)) => {
+                // Replies to our own edits carry a ref, anything else was broadcast
+                if cmd.r#ref.is_empty() {
+                    if let Some(sent) = self.sent.edits.lock().unwrap().get(&packet.nonce) {
+                        stats.fanout.entry("ot").or_default().push(since(*sent));
+                    }
+                }
+
+                let mut document = self.document.lock().unwrap();
+                document.version = document.version.max(packet.version);
+                for component in packet.op {
+                    match component.op_component {
+                        Some(goval::ot_op_component::OpComponent::Insert(text)) => {
+                            document.len += text.chars().count() as u32
+                        }
+                        Some(goval::ot_op_component::OpComponent::Delete(amount)) => {
+                            document.len = document.len.saturating_sub(amount)
+                        }
+                        _ => {}
+                    }
+                }
+            }
+            Some(Body::OtNewCursor(cursor)) => {
+                if let Some((position, sent)) = self.sent.cursors.lock().unwrap().get(&cursor.id) {
+                    if *position == cursor.position {
+                        stats.fanout.entry("cursor").or_default().push(since(*sent));
+                    }
+                }
+            }
+            Some(Body::Otstatus(status)) => {
+                if let Some(waiter) = self.ot_status.lock().unwrap().take() {
+                    let _ = waiter.send(status);
+                }
+            }
+            Some(Body::ChatMessage(message)) => {
+                let (markers, _) = parse_markers(&message.text);
+                for sent in markers {
+                    stats.fanout.entry("chat").or_default().push(since(sent));
+                }
+            }
+            Some(Body::Output(output)) if cmd.channel == self.channels.lock().unwrap().shell => {
+                // Markers can be split across output frames, so keep the unparsed tail around
+                let mut tail = self.shell_tail.lock().unwrap();
+                tail.push_str(&output);
+
+                let (markers, consumed) = parse_markers(&tail);
+                for sent in markers {
+                    stats.fanout.entry("shell").or_default().push(since(sent));
+                }
+
+                *tail = tail[consumed..].to_string();
+            }
+            Some(Body::Error(err)) => {
+                debug!(index = self.index, err, "Server sent error");
+                stats.errors += 1;
+            }
+            Some(Body::ProtocolError(err)) => {
+                warn!(
+                    index = self.index,
+                    err = err.text,
+                    "Server sent protocol error"
+                );
+                stats.errors += 1;
+            }
+            _ => {}
+        }
+    }
+
+    fn send(&self, channel: i32, body: Body) -> Result<()> {
+        self.send_inner(channel, body, String::new())
+    }
+
+    fn send_tracked(
+        &self,
+        kind: &'static str,
+        channel: i32,
+        body: Body,
+        waiter: Option<oneshot::Sender<goval::Command>>,
+    ) -> Result<()> {
+        let r#ref = format!(
+            "bench-{}-{}",
+            self.index,
+            self.next_ref.fetch_add(1, Ordering::Relaxed)
+        );
+
+        self.pending.lock().unwrap().insert(
+            r#ref.clone(),
+            Pending {
+                kind,
+                sent: Instant::now(),
+                waiter,
+            },
+        );
+
+        self.send_inner(channel, body, r#ref)
+    }
+
+    fn send_inner(&self, channel: i32, body: Body, r#ref: String) -> Result<()> {
+        let cmd = goval::Command {
+            channel,
+            r#ref,
+            body: Some(body),
+            ..Default::default()
+        };
+
+        self.outbound
+            .send(WsMessage::Binary(cmd.encode_to_vec()))
+            .map_err(|_| format_err!("Connection for session {} was closed", self.index))?;
+        self.stats.lock().unwrap().sent += 1;
+        Ok(())
+    }
+
+    async fn request(
+        &self,
+        kind: &'static str,
+        channel: i32,
+        body: Body,
+    ) -> Result<goval::Command> {
+        let (tx, rx) = oneshot::channel();
+        self.send_tracked(kind, channel, body, Some(tx))?;
+
+        let reply = tokio::time::timeout(Duration::from_secs(10), rx)
+            .await
+            .map_err(|_| format_err!("Timed out waiting for {} reply", kind))??;
+
+        match reply.body {
+            Some(Body::Error(err)) => Err(format_err!("{} failed: {}", kind, err)),
+            Some(Body::ProtocolError(err)) => Err(format_err!("{} failed: {}", kind, err.text)),
+            _ => Ok(reply),
+        }
+    }
+
+    async fn open(&self, service: &str, name: &str) -> Result<i32> {
+        let open_chan = goval::OpenChannel {
+            service: service.to_string(),
+            name: name.to_string(),
+            action: goval::open_channel::Action::AttachOrCreate.into(),
+            ..Default::default()
+        };
+
+        match self
+            .request("open", 0, Body::OpenChan(open_chan))
+            .await?
+            .body
+        {
+            Some(Body::OpenChanRes(res)) => Ok(res.id),
+            other => Err(format_err!(
+                "Unexpected reply opening {} channel: {:?}",
+                service,
+                other
+            )),
+        }
+    }
+
+    async fn setup(&self, options: &Options, create_file: bool) -> Result<()> {
+        if create_file {
+            let files = self.open("gcsfiles", "").await?;
+            let file = goval::File {
+                path: options.file.clone(),
+                ..Default::default()
+            };
+            self.request("write", files, Body::Write(file)).await?;
+        }
+
+        let (status_tx, status_rx) = oneshot::channel();
+        *self.ot_status.lock().unwrap() = Some(status_tx);
+
+        let ot = self.open("ot", &format!("ot:{}", options.file)).await?;
+        let status = tokio::time::timeout(Duration::from_secs(10), status_rx)
+            .await
+            .map_err(|_| format_err!("Timed out waiting for OtStatus"))??;
+
+        if status.linked_file.is_some() {
+            let mut document = self.document.lock().unwrap();
+            document.version = document.version.max(status.version);
+            document.len = status.contents.chars().count() as u32;
+        } else {
+            let link = goval::OtLinkFile {
+                file: Some(goval::File {
+                    path: options.file.clone(),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            };
+
+            match self.request("link", ot, Body::OtLinkFile(link)).await?.body {
+                Some(Body::OtLinkFileResponse(res)) => {
+                    let mut document = self.document.lock().unwrap();
+                    document.version = document.version.max(res.version);
+                    document.len = res
+                        .linked_file
+                        .map(|file| String::from_utf8_lossy(&file.content).chars().count())
+                        .unwrap_or(0) as u32;
+                }
+                other => return Err(format_err!("Unexpected reply to otLinkFile: {:?}", other)),
+            }
+        }
+
+        let presence = self.open("presence", "presence").await?;
+        self.send(
+            presence,
+            Body::OpenFile(goval::OpenFile {
+                file: options.file.clone(),
+            }),
+        )?;
+
+        let chat = self.open("chat", "chat").await?;
+        let shell = self.open("shell", "homeval-bench").await?;
+
+        *self.channels.lock().unwrap() = Channels { ot, chat, shell };
+
+        Ok(())
+    }
+
+    fn edit(&self) -> Result<()> {
+        let mut rng = rand::thread_rng();
+        let (version, len) = {
+            let document = self.document.lock().unwrap();
+            (document.version, document.len)
+        };
+
+        let position = rng.gen_range(0..=len);
+        let mut op = vec![];
+        let (skip, change) = if position > 0 && rng.gen_ratio(1, 10) {
+            (position - 1, goval::ot_op_component::OpComponent::Delete(1))
+        } else {
+            let typed = b"abcdefghijklmnopqrstuvwxyz \n"[rng.gen_range(0..28)] as char;
+            (
+                position,
+                goval::ot_op_component::OpComponent::Insert(typed.to_string()),
+            )
+        };
+
+        if skip > 0 {
+            op.push(goval::OtOpComponent {
+                op_component: Some(goval::ot_op_component::OpComponent::Skip(skip)),
+            });
+        }
+        op.push(goval::OtOpComponent {
+            op_component: Some(change),
+        });
+
+        let nonce = self.sent.next_nonce.fetch_add(1, Ordering::Relaxed) + 1;
+        let packet = goval::OtPacket {
+            spooky_version: version + 1,
+            version: version + 1,
+            op,
+            user_id: self.user_id,
+            nonce,
+            ..Default::default()
+        };
+        let mut edits = self.sent.edits.lock().unwrap();
+        edits.insert(nonce, now_nanos());
+        while edits.len() > TRACKED_EDITS {
+            edits.pop_first();
+        }
+        drop(edits);
+
+        let ot = self.channels.lock().unwrap().ot;
+        self.send_tracked("ot", ot, Body::Ot(packet), None)
+    }
+
+    fn cursor(&self) -> Result<()> {
+        let position = rand::thread_rng().gen_range(0..=self.document.lock().unwrap().len);
+        let cursor = goval::OtCursor {
+            position,
+            selection_start: position,
+            selection_end: position,
+            user: Some(goval::User {
+                id: self.user_id,
+                name: self.username.clone(),
+                ..Default::default()
+            }),
+            id: format!("homeval-bench-{}", self.index),
+        };
+        self.sent
+            .cursors
+            .lock()
+            .unwrap()
+            .insert(cursor.id.clone(), (position, now_nanos()));
+
+        let ot = self.channels.lock().unwrap().ot;
+        self.send(ot, Body::OtNewCursor(cursor))
+    }
+
+    fn typing(&self) -> Result<()> {
+        let typing = goval::ChatTyping {
+            username: self.username.clone(),
+            typing: rand::thread_rng().gen_bool(0.5),
+        };
+
+        let chat = self.channels.lock().unwrap().chat;
+        self.send(chat, Body::ChatTyping(typing))
+    }
+
+    fn chat(&self) -> Result<()> {
+        let message = goval::ChatMessage {
+            username: self.username.clone(),
+            text: format!("{}{}:", MARKER, now_nanos()),
+        };
+
+        let chat = self.channels.lock().unwrap().chat;
+        self.send(chat, Body::ChatMessage(message))
+    }
+
+    fn input(&self) -> Result<()> {
+        // Arithmetic expansion keeps the terminal echo of the command from matching the marker
+        let input = format!("echo {}$(({})):\n", MARKER, now_nanos());
+
+        let shell = self.channels.lock().unwrap().shell;
+        self.send(shell, Body::Input(input))
+    }
+
+    fn ping(&self) -> Result<()> {
+        self.send_tracked("ping", 0, Body::Ping(goval::Ping {}), None)
+    }
+
+    fn close(&self) {
+        let _ = self.outbound.send(WsMessage::Close(None));
+    }
+}
+
+fn now_nanos() -> u128 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos()
+}
+
+fn since(sent_nanos: u128) -> Duration {
+    Duration::from_nanos(now_nanos().saturating_sub(sent_nanos) as u64)
+}
+
+/// Returns the send times found in `text`, and how much of `text` has been
+/// consumed. Anything after that might still be the start of a marker.
+fn parse_markers(text: &str) -> (Vec<u128>, usize) {
+    let mut found = vec![];
+    let mut offset = 0;
+
+    while let Some(idx) = text[offset..].find(MARKER) {
+        let start = offset + idx + MARKER.len();
+        match text[start..].find(':') {
+            Some(end) => {
+                if let Ok(nanos) = text[start..start + end].parse() {
+                    found.push(nanos);
+                }
+                offset = start + end + 1;
+            }
+            None => return (found, offset + idx),
+        }
+    }
+
+    let mut consumed = text.len().saturating_sub(MARKER.len()).max(offset);
+    while !text.is_char_boundary(consumed) {
+        consumed += 1;
+    }
+
+    (found, consumed)
+}
+
+/// Builds an unsigned paseto carrying a `ReplToken` for the given user. Homeval
+/// falls back to the non verifying parser for these, which is enough to give
+/// each simulated session its own identity.
+fn mint_token(user_id: u32, username: &str) -> Result<String> {
+    let token = goval::ReplToken {
+        presenced: Some(goval::repl_token::Presenced {
+            bearer_id: user_id,
+            bearer_name: username.to_string(),
+        }),
+        ..Default::default()
+    };
+
+    let mut payload = general_purpose::STANDARD
+        .encode(token.encode_to_vec())
+        .into_bytes();
+    payload.extend_from_slice(&[0; 64]);
+
+    Ok(format!(
+        "v2.public.{}",
+        general_purpose::URL_SAFE_NO_PAD.encode(payload)
+    ))
+}
+
+type Generator = fn(&Client) -> Result<()>;
+
+fn spawn_traffic(
+    client: Arc<Client>,
+    rate: f64,
+    until: Instant,
+    generate: Generator,
+) -> Option<tokio::task::JoinHandle<()>> {
+    if rate <= 0.0 {
+        return None;
+    }
+
+    let period = Duration::from_secs_f64(1.0 / rate);
+    Some(tokio::spawn(async move {
+        // Spread sessions out so they don't all fire on the same tick
+        let offset = period.mul_f64(rand::thread_rng().gen_range(0.0..1.0));
+        let mut interval = tokio::time::interval_at((Instant::now() + offset).into(), period);
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+        loop {
+            interval.tick().await;
+            if Instant::now() >= until {
+                break;
+            }
+
+            if let Err(err) = generate(&client) {
+                error!(%err, index = client.index, "Error generating traffic");
+                break;
+            }
+        }
+    }))
+}
+
+fn percentile(sorted: &[Duration], pct: f64) -> Duration {
+    let idx = ((sorted.len() - 1) as f64 * pct / 100.0).round() as usize;
+    sorted[idx]
+}
+
+fn print_row(name: &str, samples: &mut [Duration]) {
+    if samples.is_empty() {
+        println!("{:<16} {:>8}", name, 0);
+        return;
+    }
+
+    samples.sort_unstable();
+    println!(
+        "{:<16} {:>8} {:>10.2?} {:>10.2?} {:>10.2?} {:>10.2?}",
+        name,
+        samples.len(),
+        percentile(samples, 50.0),
+        percentile(samples, 90.0),
+        percentile(samples, 99.0),
+        samples[samples.len() - 1],
+    );
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    tracing_subscriber::fmt::init();
+    let options = Options::parse()?;
+
+    info!(
+        addr = options.addr,
+        sessions = options.sessions,
+        "Opening sessions"
+    );
+
+    let sent = Arc::new(Sent::default());
+    let mut clients = vec![];
+    for index in 0..options.sessions {
+        let client = Client::connect(&options, index, sent.clone()).await?;
+        client.setup(&options, index == 0).await?;
+        clients.push(client);
+
+        tokio::time::sleep(options.ramp).await;
+    }
+
+    info!(duration = ?options.duration, "All sessions attached, generating traffic");
+
+    let until = Instant::now() + options.duration;
+    let mut tasks = vec![];
+    for client in clients.iter() {
+        let generators: [(f64, Generator); 6] = [
+            (options.edit_rate, Client::edit),
+            (options.cursor_rate, Client::cursor),
+            (options.typing_rate, Client::typing),
+            (options.chat_rate, Client::chat),
+            (options.input_rate, Client::input),
+            (options.ping_rate, Client::ping),
+        ];
+
+        for (rate, generate) in generators {
+            tasks.extend(spawn_traffic(client.clone(), rate, until, generate));
+        }
+    }
+
+    for task in tasks {
+        task.await?;
+    }
+
+    // Give in flight replies and broadcasts a moment to arrive
+    tokio::time::sleep(Duration::from_secs(2)).await;
+
+    let mut total = Stats::default();
+    let mut unanswered = 0;
+    for client in clients.iter() {
+        client.close();
+        unanswered += client.pending.lock().unwrap().len();
+        total.merge(std::mem::take(&mut *client.stats.lock().unwrap()));
+    }
+
+    println!(
+        "\nhomeval-bench: {} sessions for {:?} against {}\n",
+        options.sessions, options.duration, options.addr
+    );
+    println!(
+        "{:<16} {:>8} {:>10} {:>10} {:>10} {:>10}",
+        "latency", "count", "p50", "p90", "p99", "max"
+    );
+
+    let mut replies: Vec<_> = total.replies.into_iter().collect();
+    replies.sort_by_key(|(kind, _)| *kind);
+    for (kind, mut samples) in replies {
+        print_row(&format!("reply/{}", kind), &mut samples);
+    }
+
+    let mut fanout: Vec<_> = total.fanout.into_iter().collect();
+    fanout.sort_by_key(|(kind, _)| *kind);
+    for (kind, mut samples) in fanout {
+        print_row(&format!("fanout/{}", kind), &mut samples);
+    }
+
+    println!(
+        "\nsent {} commands, received {}, {} errors, {} requests unanswered",
+        total.sent, total.received, total.errors, unanswered
+    );
+
+    Ok(())
+}
@@ -11,6 +11,7 @@ pub struct Model {
     pub crc32: i32,
     pub contents: String,
     pub history: Vec<String>,
+    pub blame: Option<Vec<u8>>,
 }
 
 #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
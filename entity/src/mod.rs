@@ -3,4 +3,6 @@
 pub mod prelude;
 
 pub mod files;
+pub mod meta;
 pub mod repldb;
+pub mod secrets;
@@ -1,4 +1,6 @@
 //! `SeaORM` Entity. Generated by sea-orm-codegen 0.11.3
 
 pub use super::files::Entity as Files;
+pub use super::meta::Entity as Meta;
 pub use super::repldb::Entity as ReplDb;
+pub use super::secrets::Entity as Secrets;
@@ -2,6 +2,9 @@ pub use sea_orm_migration::prelude::*;
 
 mod m20230611_000001_create_files_table;
 mod m20230616_000049_create_repldb_table;
+mod m20261019_000001_create_secrets_table;
+mod m20261019_000002_create_meta_table;
+mod m20261019_000003_add_files_blame;
 
 pub struct Migrator;
 
@@ -11,6 +14,9 @@ impl MigratorTrait for Migrator {
         vec![
             Box::new(m20230611_000001_create_files_table::Migration),
             Box::new(m20230616_000049_create_repldb_table::Migration),
+            Box::new(m20261019_000001_create_secrets_table::Migration),
+            Box::new(m20261019_000002_create_meta_table::Migration),
+            Box::new(m20261019_000003_add_files_blame::Migration),
         ]
     }
 }
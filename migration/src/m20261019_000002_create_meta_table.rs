@@ -0,0 +1,42 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .create_table(
+                Table::create()
+                    .table(Meta::Table)
+                    .if_not_exists()
+                    .col(ColumnDef::new(Meta::Key).string().not_null().primary_key())
+                    .col(ColumnDef::new(Meta::Data).binary().not_null())
+                    .col(ColumnDef::new(Meta::Generation).big_integer().not_null())
+                    .col(
+                        ColumnDef::new(Meta::Metageneration)
+                            .big_integer()
+                            .not_null(),
+                    )
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .drop_table(Table::drop().table(Meta::Table).to_owned())
+            .await
+    }
+}
+
+/// Learn more at https://docs.rs/sea-query#iden
+#[derive(Iden)]
+enum Meta {
+    Table,
+    Key,
+    Data,
+    Generation,
+    Metageneration,
+}
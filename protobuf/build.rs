@@ -7,6 +7,9 @@ fn main() {
     // Compile protobufs
     let mut config = Config::new();
     config
+        .file_descriptor_set_path(
+            std::path::PathBuf::from(std::env::var("OUT_DIR").unwrap()).join("goval.bin"),
+        )
         .compile_protos(&["src/goval.proto"], &["src/"])
         .unwrap();
 }
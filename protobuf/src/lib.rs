@@ -1,3 +1,6 @@
 #![allow(clippy::all)]
 // Include the `goval` module, which is generated from goval.proto.
 include!(concat!(env!("OUT_DIR"), "/goval.rs"));
+
+// Encoded `FileDescriptorSet` for goval.proto, used to decode commands dynamically.
+pub const FILE_DESCRIPTOR_SET: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/goval.bin"));
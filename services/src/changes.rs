@@ -0,0 +1,227 @@
+use crate::{paths, snapshot_store, ChannelMessage, FSEvent, FSWatcher};
+use anyhow::Result;
+use std::{
+    collections::{HashMap, HashSet},
+    io::Read,
+    path::Path,
+    sync::LazyLock,
+};
+use tokio::sync::{Mutex, OnceCell};
+use tracing::{debug, warn};
+
+// Content hashes of every file in the workspace, kept up to date from the
+// shared watcher. Each change bumps the generation so "what changed since X"
+// is just comparing generations.
+static INDEX: LazyLock<Mutex<Index>> = LazyLock::new(|| Mutex::new(Index::default()));
+static STARTED: OnceCell<()> = OnceCell::const_new();
+
+struct Entry {
+    // None once the file has been removed, kept so removals are reported too
+    crc32: Option<u32>,
+    generation: u64,
+}
+
+#[derive(Default)]
+struct Index {
+    files: HashMap<String, Entry>,
+    generation: u64,
+    // Generation the last snapshot was taken at
+    snapshot: u64,
+    // Generation each session last checked at
+    checked: HashMap<i32, u64>,
+}
+
+impl Index {
+    fn update(&mut self, path: String, crc32: Option<u32>) {
+        if let Some(entry) = self.files.get(&path) {
+            if entry.crc32 == crc32 {
+                return;
+            }
+        } else if crc32.is_none() {
+            return;
+        }
+
+        self.generation += 1;
+        let generation = self.generation;
+        self.files.insert(path, Entry { crc32, generation });
+    }
+}
+
+/// Builds the index on first use, hashing the whole workspace
+async fn start() -> Result<()> {
+    STARTED
+        .get_or_try_init(|| async {
+            let (sender, mut receiver) = tokio::sync::mpsc::unbounded_channel();
+            let mut watcher = FSWatcher::new(sender).await?;
+            watcher.watch_prefix("").await?;
+
+            let scanned = scan(String::new()).await?;
+            let changed = snapshot_store::changed_since_latest().await?;
+            debug!(
+                files = scanned.len(),
+                changed = changed.as_ref().map(Vec::len),
+                "Built change index"
+            );
+
+            // Whatever differs from the last snapshot counts as changed, and
+            // without one every file does
+            let mut index = INDEX.lock().await;
+            index.generation = 1;
+            let changed: Option<HashSet<String>> =
+                changed.map(|changed| changed.into_iter().collect());
+            for (path, crc32) in scanned {
+                let generation = match &changed {
+                    Some(changed) => u64::from(changed.contains(&path)),
+                    None => 1,
+                };
+                index.files.insert(
+                    path,
+                    Entry {
+                        crc32: Some(crc32),
+                        generation,
+                    },
+                );
+            }
+            for path in changed.into_iter().flatten() {
+                index.files.entry(path).or_insert(Entry {
+                    crc32: None,
+                    generation: 1,
+                });
+            }
+            drop(index);
+
+            tokio::spawn(async move {
+                // Lives as long as the process, the index is never torn down
+                let _watcher = watcher;
+                while let Some(message) = receiver.recv().await {
+                    if let ChannelMessage::FSEvent(event) = message {
+                        if let Err(err) = handle(event).await {
+                            warn!(%err, "Error updating change index");
+                        }
+                    }
+                }
+            });
+
+            Ok(())
+        })
+        .await
+        .map(|_| ())
+}
+
+async fn handle(event: FSEvent) -> Result<()> {
+    match event {
+        FSEvent::Create(path) | FSEvent::Modify(path) | FSEvent::Remove(path) => {
+            refresh(path).await
+        }
+        FSEvent::Rename(from, to) => {
+            refresh(from).await?;
+            refresh(to).await
+        }
+        FSEvent::Rescan => refresh(String::new()).await,
+        FSEvent::Err(_) => Ok(()),
+    }
+}
+
+/// Rehashes everything under `prefix`, anything no longer there is marked removed
+async fn refresh(prefix: String) -> Result<()> {
+    if prefix.split('/').any(paths::is_always_ignored) {
+        return Ok(());
+    }
+
+    let scanned: HashMap<String, u32> = scan(prefix.clone()).await?.into_iter().collect();
+
+    let mut index = INDEX.lock().await;
+    let removed: Vec<String> = index
+        .files
+        .iter()
+        .filter(|(path, entry)| {
+            entry.crc32.is_some() && paths::is_under(path, &prefix) && !scanned.contains_key(*path)
+        })
+        .map(|(path, _)| path.clone())
+        .collect();
+
+    for path in removed {
+        index.update(path, None);
+    }
+    for (path, crc32) in scanned {
+        index.update(path, Some(crc32));
+    }
+
+    Ok(())
+}
+
+// Hashes every file under `prefix` (or just `prefix` if it's a file), symlinks
+// aren't followed
+async fn scan(prefix: String) -> Result<Vec<(String, u32)>> {
+    tokio::task::spawn_blocking(move || {
+        let root = paths::workspace_root();
+        let mut found = vec![];
+
+        for (relative, _) in paths::walk(&prefix)? {
+            match hash(&root.join(&relative)) {
+                Ok(crc32) => found.push((relative, crc32)),
+                Err(err) => debug!(%err, path = relative, "Couldn't hash file"),
+            }
+        }
+
+        Ok(found)
+    })
+    .await?
+}
+
+fn hash(path: &Path) -> std::io::Result<u32> {
+    let mut file = std::fs::File::open(path)?;
+    let mut hasher = crc32fast::Hasher::new();
+    let mut buffer = vec![0; 64 * 1024];
+
+    loop {
+        let read = file.read(&mut buffer)?;
+        if read == 0 {
+            return Ok(hasher.finalize());
+        }
+        hasher.update(&buffer[..read]);
+    }
+}
+
+/// Files changed since the last snapshot or since `session` last checked,
+/// whichever was more recent
+pub async fn check(session: i32) -> Result<Vec<String>> {
+    start().await?;
+
+    let mut index = INDEX.lock().await;
+    let since = index
+        .snapshot
+        .max(index.checked.get(&session).copied().unwrap_or_default());
+
+    let mut changed: Vec<String> = index
+        .files
+        .iter()
+        .filter(|(_, entry)| entry.generation > since)
+        .map(|(path, _)| path.clone())
+        .collect();
+    changed.sort();
+
+    let generation = index.generation;
+    index.checked.insert(session, generation);
+
+    Ok(changed)
+}
+
+/// Makes the current state of the workspace the baseline for `check`
+pub async fn mark_snapshot() -> Result<()> {
+    start().await?;
+
+    let mut index = INDEX.lock().await;
+    index.snapshot = index.generation;
+
+    // Removals from before the snapshot can't be reported anymore
+    let snapshot = index.snapshot;
+    index
+        .files
+        .retain(|_, entry| entry.crc32.is_some() || entry.generation > snapshot);
+    Ok(())
+}
+
+pub async fn forget(session: i32) {
+    INDEX.lock().await.checked.remove(&session);
+}
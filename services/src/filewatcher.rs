@@ -0,0 +1,213 @@
+pub struct FileWatcher {
+    sender: tokio::sync::mpsc::UnboundedSender<ChannelMessage>,
+    // Subscribed to the shared watcher on the first subscription
+    watcher: Option<FSWatcher>,
+    // Paths each session subscribed to, an empty path covers the whole workspace
+    subscriptions: HashMap<i32, Vec<String>>,
+}
+use crate::{paths, ChannelInfo, ChannelMessage, FSEvent, FSWatcher, SendSessions};
+use std::collections::HashMap;
+use tracing::{debug, error};
+
+use super::traits;
+use anyhow::{format_err, Result};
+use async_trait::async_trait;
+
+impl FileWatcher {
+    pub fn new(sender: tokio::sync::mpsc::UnboundedSender<ChannelMessage>) -> Self {
+        Self {
+            sender,
+            watcher: None,
+            subscriptions: HashMap::new(),
+        }
+    }
+
+    async fn ensure_watching(&mut self) -> Result<()> {
+        if self.watcher.is_none() {
+            let mut watcher = FSWatcher::new(self.sender.clone()).await?;
+            watcher.watch_prefix("").await?;
+            self.watcher = Some(watcher);
+        }
+
+        Ok(())
+    }
+
+    fn subscribers(&self, paths: &[&str]) -> Vec<i32> {
+        self.subscriptions
+            .iter()
+            .filter(|(_, subscribed)| {
+                subscribed
+                    .iter()
+                    .any(|prefix| paths.iter().any(|path| paths::is_under(path, prefix)))
+            })
+            .map(|(session, _)| *session)
+            .collect()
+    }
+
+    // Events were lost, so tell everyone that what they subscribed to changed
+    // and let them read it again
+    async fn rescan(&self, info: &ChannelInfo) -> Result<()> {
+        for (session, subscribed) in &self.subscriptions {
+            for path in subscribed {
+                let path = if path.is_empty() { "." } else { path };
+                let notif = goval::Command {
+                    body: Some(goval::command::Body::FileEvent(goval::FileEvent {
+                        file: Some(file(path.to_string()).await),
+                        dest: None,
+                        op: goval::file_event::Op::Modify.into(),
+                    })),
+                    ..Default::default()
+                };
+                info.send(notif, SendSessions::Only(*session)).await?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Whether events for `path` should be kept from clients
+fn is_ignored(path: &str, hidden: &[glob::Pattern]) -> bool {
+    let mut parents = String::new();
+    for part in path.split('/') {
+        if !parents.is_empty() {
+            parents.push('/');
+        }
+        parents.push_str(part);
+
+        if paths::is_always_ignored(part)
+            || hidden
+                .iter()
+                .any(|pattern| pattern.matches(part) || pattern.matches(&parents))
+        {
+            return true;
+        }
+    }
+
+    false
+}
+
+async fn file(path: String) -> goval::File {
+    let r#type = match tokio::fs::symlink_metadata(paths::workspace_root().join(&path)).await {
+        Ok(metadata) if metadata.is_dir() => goval::file::Type::Directory,
+        _ => goval::file::Type::Regular,
+    };
+
+    goval::File {
+        path,
+        r#type: r#type.into(),
+        ..Default::default()
+    }
+}
+
+#[async_trait]
+impl traits::Service for FileWatcher {
+    async fn message(
+        &mut self,
+        _info: &ChannelInfo,
+        message: goval::Command,
+        session: i32,
+    ) -> Result<Option<goval::Command>> {
+        let body = match message.body.clone() {
+            None => return Err(format_err!("Expected command body")),
+            Some(body) => body,
+        };
+
+        match body {
+            goval::command::Body::SubscribeFile(subscribe) => {
+                let mut subscribed = vec![];
+                if subscribe.files.is_empty() {
+                    subscribed.push(String::new());
+                }
+                for file in subscribe.files {
+                    subscribed.push(paths::resolve(&file.path)?.relative);
+                }
+
+                self.ensure_watching().await?;
+                debug!(session, paths = ?subscribed, "Subscribed to file events");
+                self.subscriptions.insert(session, subscribed);
+
+                Ok(Some(goval::Command {
+                    body: Some(goval::command::Body::Ok(goval::Ok {})),
+                    ..Default::default()
+                }))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    async fn fsevent(&mut self, info: &ChannelInfo, event: FSEvent) -> Result<()> {
+        let hidden: Vec<glob::Pattern> = info
+            .dotreplit
+            .read()
+            .await
+            .hidden
+            .iter()
+            .flatten()
+            .filter_map(|pattern| glob::Pattern::new(pattern.trim_matches('/')).ok())
+            .collect();
+        let ignored = |path: &str| is_ignored(path, &hidden);
+
+        let (op, path, dest) = match event {
+            FSEvent::Create(path) => (goval::file_event::Op::Create, path, None),
+            FSEvent::Modify(path) => (goval::file_event::Op::Modify, path, None),
+            FSEvent::Remove(path) => (goval::file_event::Op::Remove, path, None),
+            // A move in or out of an ignored path is a create or remove as far
+            // as clients are concerned, atomic writes show up like this
+            FSEvent::Rename(from, to) => match (ignored(&from), ignored(&to)) {
+                (false, false) => (goval::file_event::Op::Move, from, Some(to)),
+                (true, false) => (goval::file_event::Op::Create, to, None),
+                (false, true) => (goval::file_event::Op::Remove, from, None),
+                (true, true) => return Ok(()),
+            },
+            FSEvent::Rescan => return self.rescan(info).await,
+            FSEvent::Err(err) => {
+                error!(err, "Error in FS event listener");
+                return Ok(());
+            }
+        };
+
+        if ignored(&path) {
+            return Ok(());
+        }
+
+        let sessions = match &dest {
+            Some(dest) => self.subscribers(&[&path, dest]),
+            None => self.subscribers(&[&path]),
+        };
+        if sessions.is_empty() {
+            return Ok(());
+        }
+
+        let event = goval::FileEvent {
+            file: Some(file(path).await),
+            dest: match dest {
+                Some(dest) => Some(file(dest).await),
+                None => None,
+            },
+            op: op.into(),
+        };
+
+        for session in sessions {
+            let notif = goval::Command {
+                body: Some(goval::command::Body::FileEvent(event.clone())),
+                ..Default::default()
+            };
+            info.send(notif, SendSessions::Only(session)).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn detach(&mut self, _info: &ChannelInfo, session: i32) -> Result<()> {
+        self.subscriptions.remove(&session);
+        Ok(())
+    }
+
+    async fn shutdown(self: Box<Self>, _info: &ChannelInfo) -> Result<()> {
+        if let Some(watcher) = self.watcher {
+            watcher.shutdown().await;
+        }
+        Ok(())
+    }
+}
@@ -1,18 +1,28 @@
-pub struct GCSFiles {}
+pub struct GCSFiles {
+    transfers: Transfers,
+}
 
 use super::traits;
+use crate::{
+    atomic, changes, locks,
+    meta::{self, PreconditionFailed},
+    paths, secrets,
+    transfers::Transfers,
+    PathEscape,
+};
 use anyhow::{format_err, Result};
 use async_trait::async_trait;
-use tokio::{fs, io::AsyncWriteExt};
+use std::{fs::Metadata, io::ErrorKind, path::Path, time::UNIX_EPOCH};
+use tokio::fs;
 use tracing::{debug, warn};
 
 #[async_trait]
 impl traits::Service for GCSFiles {
     async fn message(
         &mut self,
-        _info: &super::types::ChannelInfo,
+        info: &super::types::ChannelInfo,
         message: goval::Command,
-        _session: i32,
+        session: i32,
     ) -> Result<Option<goval::Command>> {
         let body = match message.body.clone() {
             None => return Err(format_err!("Expected command body")),
@@ -21,36 +31,28 @@ impl traits::Service for GCSFiles {
 
         match body {
             goval::command::Body::Readdir(dir) => {
-                let parent = std::path::Path::new(&dir.path);
-
-                let mut res: Vec<goval::File> = vec![];
-                let mut iter = fs::read_dir(&parent).await?;
-
-                while let Some(file) = iter.next_entry().await? {
-                    let mut entry = goval::File::default();
-                    if let Some(str_path) = file.path().strip_prefix(parent)?.to_str() {
-                        entry.path = str_path.to_string();
-
-                        let ftype = file.metadata().await?;
-                        if ftype.is_dir() {
-                            entry.r#type = goval::file::Type::Directory.into();
-                        } else {
-                            entry.r#type = goval::file::Type::Regular.into();
-                        }
-
-                        res.push(entry);
-                    } else {
-                        return Err(format_err!("Got none from Path#to_str in gcsfiles#readdir"));
+                let resolved = paths::resolve(&dir.path)?;
+                let files = match readdir(&resolved.absolute).await {
+                    Ok(files) => files,
+                    Err(err) => {
+                        warn!(%err, path = dir.path, "Error reading directory in gcsfiles");
+                        return Ok(Some(goval::Command {
+                            body: Some(goval::command::Body::Error(format!(
+                                "{}: {}",
+                                dir.path, err
+                            ))),
+                            ..Default::default()
+                        }));
                     }
-                }
+                };
 
                 let mut ret = goval::Command::default();
-                let _inner = goval::Files { files: res };
+                let _inner = goval::Files { files };
                 ret.body = Some(goval::command::Body::Files(_inner));
                 Ok(Some(ret))
             }
             goval::command::Body::Mkdir(dir) => {
-                fs::create_dir_all(dir.path).await?;
+                fs::create_dir_all(paths::resolve(&dir.path)?.absolute).await?;
                 let ret = goval::Command {
                     body: Some(goval::command::Body::Ok(goval::Ok {})),
                     ..Default::default()
@@ -59,9 +61,9 @@ impl traits::Service for GCSFiles {
             }
             goval::command::Body::Read(file) => {
                 debug!(path = file.path, "File path");
-                let contents = match file.path.as_str() {
-                    // TODO: Read this from in the db
-                    ".env" => vec![],
+                let resolved = paths::resolve(&file.path)?;
+                let contents = match resolved.relative.as_str() {
+                    _ if secrets::is_secret(&resolved) => secrets::read_dotenv().await?,
                     ".config/goval/info" => {
                         let val = serde_json::json!({
                             "server": "homeval",
@@ -70,13 +72,14 @@ impl traits::Service for GCSFiles {
                             "authors": vec!["PotentialStyx <62217716+PotentialStyx@users.noreply.github.com>"],
                             "repository": "https://github.com/goval-community/homeval",
                             "description": "", // TODO: do dis
-                            "uptime": 0, // TODO: impl fo realz
-                            "services": super::IMPLEMENTED_SERVICES
+                            "uptime": super::registry::uptime(),
+                            "services": super::SERVICES.iter().map(|service| service.name).collect::<Vec<_>>(),
+                            "capabilities": super::SERVICES.iter().map(|service| service.to_json()).collect::<Vec<_>>()
                         });
 
                         val.to_string().as_bytes().to_vec()
                     }
-                    _ => match fs::read(&file.path).await {
+                    _ => match fs::read(&resolved.absolute).await {
                         Err(err) => {
                             warn!(error = %err, "Error reading file in gcsfiles");
                             let ret = goval::Command {
@@ -103,11 +106,24 @@ impl traits::Service for GCSFiles {
                 Ok(Some(ret))
             }
             goval::command::Body::Remove(file) => {
-                let stat = fs::metadata(&file.path).await?;
+                let resolved = paths::resolve(&file.path)?;
+                if resolved.relative.is_empty() {
+                    return Err(PathEscape(file.path).into());
+                }
+
+                if secrets::is_secret(&resolved) {
+                    secrets::write_dotenv(b"").await?;
+                    return Ok(Some(goval::Command {
+                        body: Some(goval::command::Body::Ok(goval::Ok {})),
+                        ..Default::default()
+                    }));
+                }
+
+                let stat = fs::metadata(&resolved.absolute).await?;
                 if stat.is_dir() {
-                    fs::remove_dir_all(&file.path).await?
+                    fs::remove_dir_all(&resolved.absolute).await?
                 } else {
-                    fs::remove_file(&file.path).await?
+                    fs::remove_file(&resolved.absolute).await?
                 }
 
                 let ret = goval::Command {
@@ -117,7 +133,27 @@ impl traits::Service for GCSFiles {
                 Ok(Some(ret))
             }
             goval::command::Body::Move(move_req) => {
-                fs::rename(move_req.old_path, move_req.new_path).await?;
+                let old = paths::resolve(&move_req.old_path)?;
+                let new = paths::resolve(&move_req.new_path)?;
+                if old.relative.is_empty() {
+                    return Err(PathEscape(move_req.old_path).into());
+                }
+                if new.relative.is_empty() {
+                    return Err(PathEscape(move_req.new_path).into());
+                }
+                match (secrets::is_secret(&old), secrets::is_secret(&new)) {
+                    (true, true) => {}
+                    (true, false) => {
+                        atomic::write_atomic(&new.absolute, &secrets::read_dotenv().await?).await?;
+                        secrets::write_dotenv(b"").await?;
+                    }
+                    (false, true) => {
+                        secrets::write_dotenv(&fs::read(&old.absolute).await?).await?;
+                        fs::remove_file(&old.absolute).await?;
+                    }
+                    (false, false) => fs::rename(old.absolute, new.absolute).await?,
+                }
+
                 let ret = goval::Command {
                     body: Some(goval::command::Body::Ok(goval::Ok {})),
                     ..Default::default()
@@ -125,32 +161,258 @@ impl traits::Service for GCSFiles {
                 Ok(Some(ret))
             }
             goval::command::Body::Write(_file) => {
-                // TODO: Store this in the db
-                if &_file.path == ".env" {
-                    let ret = goval::Command {
-                        body: Some(goval::command::Body::Ok(goval::Ok {})),
+                let resolved = paths::resolve(&_file.path)?;
+                if resolved.relative.is_empty() {
+                    return Err(PathEscape(_file.path).into());
+                }
+
+                if secrets::is_secret(&resolved) {
+                    secrets::write_dotenv(&_file.content).await?;
+                } else {
+                    atomic::write_atomic(&resolved.absolute, &_file.content).await?;
+                }
+
+                let ret = goval::Command {
+                    body: Some(goval::command::Body::Ok(goval::Ok {})),
+                    ..Default::default()
+                };
+                Ok(Some(ret))
+            }
+            goval::command::Body::Stat(file) => {
+                let resolved = paths::resolve(&file.path)?;
+                if secrets::is_secret(&resolved) {
+                    let contents = secrets::read_dotenv().await?;
+                    return Ok(Some(goval::Command {
+                        body: Some(goval::command::Body::StatRes(goval::StatResult {
+                            exists: !contents.is_empty(),
+                            r#type: goval::file::Type::Regular.into(),
+                            size: contents.len() as i64,
+                            file_mode: "-rw-------".to_string(),
+                            ..Default::default()
+                        })),
                         ..Default::default()
-                    };
+                    }));
+                }
 
-                    return Ok(Some(ret));
+                let stat = match fs::metadata(&resolved.absolute).await {
+                    Ok(stat) => stat,
+                    Err(err) if err.kind() == ErrorKind::NotFound => {
+                        let ret = goval::Command {
+                            body: Some(goval::command::Body::StatRes(goval::StatResult {
+                                exists: false,
+                                ..Default::default()
+                            })),
+                            ..Default::default()
+                        };
+                        return Ok(Some(ret));
+                    }
+                    Err(err) => {
+                        warn!(%err, path = file.path, "Error reading file stats in gcsfiles");
+                        return Ok(Some(goval::Command {
+                            body: Some(goval::command::Body::Error(format!(
+                                "{}: {}",
+                                file.path, err
+                            ))),
+                            ..Default::default()
+                        }));
+                    }
+                };
+
+                let file_type = if stat.is_dir() {
+                    goval::file::Type::Directory
+                } else {
+                    goval::file::Type::Regular
+                };
+
+                let ret = goval::Command {
+                    body: Some(goval::command::Body::StatRes(goval::StatResult {
+                        exists: true,
+                        r#type: file_type.into(),
+                        size: stat.len() as i64,
+                        file_mode: file_mode(&stat),
+                        mod_time: mod_time(&stat),
+                    })),
+                    ..Default::default()
+                };
+                Ok(Some(ret))
+            }
+            goval::command::Body::TryRemove(file) => {
+                // Same as remove, but the client doesn't care if it worked
+                match paths::resolve(&file.path) {
+                    Ok(resolved) if secrets::is_secret(&resolved) => {
+                        if let Err(err) = secrets::write_dotenv(b"").await {
+                            debug!(%err, path = file.path, "tryRemove failed");
+                        }
+                    }
+                    Ok(resolved) if !resolved.relative.is_empty() => {
+                        let removed = match fs::metadata(&resolved.absolute).await {
+                            Ok(stat) if stat.is_dir() => {
+                                fs::remove_dir_all(&resolved.absolute).await
+                            }
+                            Ok(_) => fs::remove_file(&resolved.absolute).await,
+                            Err(err) => Err(err),
+                        };
+
+                        if let Err(err) = removed {
+                            debug!(%err, path = file.path, "tryRemove failed");
+                        }
+                    }
+                    Ok(_) => debug!("tryRemove on the workspace root, ignoring"),
+                    Err(err) => debug!(%err, "tryRemove failed"),
                 }
 
-                let mut file = fs::OpenOptions::new()
-                    .write(true)
-                    .create(true)
-                    .open(_file.path)
-                    .await?;
-                file.set_len(0).await?;
-                file.write_all(&_file.content).await?;
                 let ret = goval::Command {
                     body: Some(goval::command::Body::Ok(goval::Ok {})),
                     ..Default::default()
                 };
                 Ok(Some(ret))
             }
-            goval::command::Body::Stat(_) => {
-                // TODO: impl
-                Ok(None)
+            // There's no mirror to persist to, so both just make sure the file
+            // has hit the disk
+            goval::command::Body::Persist(file) | goval::command::Body::PersistMirror(file) => {
+                atomic::persist(&paths::resolve(&file.path)?.absolute).await?;
+                let ret = goval::Command {
+                    body: Some(goval::command::Body::Ok(goval::Ok {})),
+                    ..Default::default()
+                };
+                Ok(Some(ret))
+            }
+            goval::command::Body::CheckChanges(_) => {
+                let files = changes::check(session)
+                    .await?
+                    .into_iter()
+                    .map(|path| goval::File {
+                        path,
+                        ..Default::default()
+                    })
+                    .collect();
+
+                Ok(Some(goval::Command {
+                    body: Some(goval::command::Body::ChangedFiles(goval::Files { files })),
+                    ..Default::default()
+                }))
+            }
+            goval::command::Body::ReadMetaRequest(request) => {
+                let response = match meta::read(&request.key).await? {
+                    Some(value) => goval::ReadMetaResponse {
+                        key: request.key,
+                        exists: true,
+                        data: value.data,
+                        generation: value.generation,
+                        metageneration: value.metageneration,
+                    },
+                    None => goval::ReadMetaResponse {
+                        key: request.key,
+                        ..Default::default()
+                    },
+                };
+
+                Ok(Some(goval::Command {
+                    body: Some(goval::command::Body::ReadMetaResponse(response)),
+                    ..Default::default()
+                }))
+            }
+            goval::command::Body::WriteMetaRequest(request) => {
+                let body =
+                    match meta::write(&request.key, request.data, request.preconditions).await {
+                        Ok(value) => {
+                            goval::command::Body::WriteMetaResponse(goval::WriteMetaResponse {
+                                generation: value.generation,
+                                metageneration: value.metageneration,
+                            })
+                        }
+                        Err(err) if err.is::<PreconditionFailed>() => {
+                            goval::command::Body::Error(err.to_string())
+                        }
+                        Err(err) => return Err(err),
+                    };
+
+                Ok(Some(goval::Command {
+                    body: Some(body),
+                    ..Default::default()
+                }))
+            }
+            goval::command::Body::AppendMetaRequest(request) => {
+                let body =
+                    match meta::append(&request.key, request.data, request.preconditions).await {
+                        Ok(value) => {
+                            goval::command::Body::AppendMetaResponse(goval::AppendMetaResponse {
+                                generation: value.generation,
+                                metageneration: value.metageneration,
+                            })
+                        }
+                        Err(err) if err.is::<PreconditionFailed>() => {
+                            goval::command::Body::Error(err.to_string())
+                        }
+                        Err(err) => return Err(err),
+                    };
+
+                Ok(Some(goval::Command {
+                    body: Some(body),
+                    ..Default::default()
+                }))
+            }
+            goval::command::Body::FsTakeLock(lock) => {
+                let body = if locks::take(&lock.name, info.id, session).await {
+                    goval::command::Body::Ok(goval::Ok {})
+                } else {
+                    goval::command::Body::Error(format!(
+                        "{}: lock is held by another session",
+                        lock.name
+                    ))
+                };
+
+                Ok(Some(goval::Command {
+                    body: Some(body),
+                    ..Default::default()
+                }))
+            }
+            goval::command::Body::FsReleaseLock(lock) => {
+                let body = if locks::release(&lock.name, session).await {
+                    goval::command::Body::Ok(goval::Ok {})
+                } else {
+                    goval::command::Body::Error(format!("{}: lock is not held", lock.name))
+                };
+
+                Ok(Some(goval::Command {
+                    body: Some(body),
+                    ..Default::default()
+                }))
+            }
+            goval::command::Body::TransferStart(start) => {
+                let body = self.transfers.start(info.id, session, start).await?;
+                Ok(Some(goval::Command {
+                    body: Some(body),
+                    ..Default::default()
+                }))
+            }
+            goval::command::Body::TransferChunk(chunk) => {
+                let body = self.transfers.chunk(session, chunk).await?;
+                Ok(Some(goval::Command {
+                    body: Some(body),
+                    ..Default::default()
+                }))
+            }
+            goval::command::Body::TransferComplete(complete) => {
+                let body = self.transfers.complete(session, complete).await?;
+                Ok(Some(goval::Command {
+                    body: Some(body),
+                    ..Default::default()
+                }))
+            }
+            goval::command::Body::Transfer(request) => {
+                let body = self.transfers.pull(session, request).await?;
+                Ok(Some(goval::Command {
+                    body: Some(body),
+                    ..Default::default()
+                }))
+            }
+            goval::command::Body::TransferCancel(cancel) => {
+                let body = self.transfers.cancel(session, &cancel.id).await;
+                Ok(Some(goval::Command {
+                    body: Some(body),
+                    ..Default::default()
+                }))
             }
             _ => {
                 warn!(cmd = ?message, "Unknown gcsfiles command");
@@ -158,4 +420,111 @@ impl traits::Service for GCSFiles {
             }
         }
     }
+
+    async fn detach(&mut self, info: &super::types::ChannelInfo, session: i32) -> Result<()> {
+        self.transfers.detach(session).await;
+        locks::release_session(info.id, session).await;
+        changes::forget(session).await;
+        Ok(())
+    }
+
+    async fn shutdown(mut self: Box<Self>, info: &super::types::ChannelInfo) -> Result<()> {
+        self.transfers.shutdown().await;
+        locks::release_channel(info.id).await;
+        Ok(())
+    }
+}
+
+impl GCSFiles {
+    pub fn new() -> Self {
+        Self {
+            transfers: Transfers::default(),
+        }
+    }
+}
+
+async fn readdir(parent: &Path) -> Result<Vec<goval::File>> {
+    let mut res: Vec<goval::File> = vec![];
+    let mut iter = fs::read_dir(&parent).await?;
+
+    while let Some(file) = iter.next_entry().await? {
+        let path = file.path();
+        if path == paths::workspace_root().join(paths::STATE_DIR) {
+            continue;
+        }
+
+        let name = match file.file_name().into_string() {
+            Ok(name) => name,
+            Err(name) => {
+                warn!(
+                    ?name,
+                    "Skipping file with a non utf-8 name in gcsfiles#readdir"
+                );
+                continue;
+            }
+        };
+
+        // Follows symlinks, so linked directories are listed as directories
+        let stat = match fs::metadata(&path).await {
+            Ok(stat) => stat,
+            Err(err) => {
+                warn!(%err, ?path, "Skipping unreadable file in gcsfiles#readdir");
+                continue;
+            }
+        };
+
+        let file_type = if stat.is_dir() {
+            goval::file::Type::Directory
+        } else {
+            goval::file::Type::Regular
+        };
+
+        res.push(goval::File {
+            path: name,
+            r#type: file_type.into(),
+            size: stat.len() as i64,
+            mod_time: mod_time(&stat),
+            ..Default::default()
+        });
+    }
+
+    Ok(res)
+}
+
+fn mod_time(stat: &Metadata) -> i64 {
+    stat.modified()
+        .ok()
+        .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+        .map(|time| time.as_secs() as i64)
+        .unwrap_or_default()
+}
+
+// Formatted like go's `fs.FileMode#String`, eg. `drwxr-xr-x`
+fn file_mode(stat: &Metadata) -> String {
+    let kind = if stat.is_dir() { 'd' } else { '-' };
+
+    #[cfg(unix)]
+    let mode = {
+        use std::os::unix::fs::PermissionsExt;
+        stat.permissions().mode()
+    };
+
+    #[cfg(not(unix))]
+    let mode = if stat.permissions().readonly() {
+        0o444
+    } else {
+        0o666
+    };
+
+    let mut out = String::with_capacity(10);
+    out.push(kind);
+    for (bit, flag) in "rwxrwxrwx".chars().enumerate() {
+        if mode & (1 << (8 - bit)) != 0 {
+            out.push(flag);
+        } else {
+            out.push('-');
+        }
+    }
+
+    out
 }
@@ -1,24 +1,39 @@
+#![feature(lazy_cell)]
+
+mod changes;
 mod chat;
 mod dotreplit;
 mod exec;
+mod filewatcher;
 mod gcsfiles;
 mod git;
+mod locks;
 mod ot;
+mod ot_blame;
+mod ot_document;
+mod ot_persist;
+mod ot_transform;
+mod ot_writer;
 mod output;
 mod presence;
+pub mod registry;
 mod shell;
 mod snapshot;
+pub mod snapshot_store;
 mod stub;
 mod toolchain;
 mod traits;
+mod transfers;
 mod types;
 
 use anyhow::format_err;
 use anyhow::Result;
+pub use ot_document::{memory_usage as ot_memory_usage, MemoryUsage as OtMemoryUsage};
+pub use registry::{service, AttachRule, ServiceInfo, SERVICES, START_TIME};
 use std::collections::HashMap;
 use std::sync::Arc;
 use tokio::sync::RwLock;
-use tracing::error;
+use tracing::{error, warn};
 pub use types::*;
 
 pub struct Channel {
@@ -35,6 +50,10 @@ impl Channel {
         dotreplit: Arc<RwLock<DotReplit>>,
         sender: tokio::sync::mpsc::UnboundedSender<ChannelMessage>,
     ) -> Result<Channel> {
+        if registry::service(&service).is_none() {
+            return Err(format_err!("Unknown service: {}", service));
+        }
+
         let info = ChannelInfo {
             id,
             name,
@@ -47,10 +66,11 @@ impl Channel {
 
         let channel: Box<dyn traits::Service + Send> = match service.as_str() {
             "chat" => Box::new(chat::Chat::new()),
-            "gcsfiles" => Box::new(gcsfiles::GCSFiles {}),
+            "gcsfiles" => Box::new(gcsfiles::GCSFiles::new()),
+            "filewatcher" => Box::new(filewatcher::FileWatcher::new(sender)),
             "presence" => Box::new(presence::Presence::new()),
             "ot" => Box::new(ot::OT::new(sender).await?),
-            "snapshot" => Box::new(snapshot::Snapshot {}),
+            "snapshot" => Box::new(snapshot::Snapshot::new(sender)),
             "output" => Box::new(output::Output::new().await),
             "shell" => Box::new(shell::Shell::new(&info).await?),
             "toolchain" => Box::new(toolchain::Toolchain {}),
@@ -110,11 +130,24 @@ impl Channel {
 // Private functions
 impl Channel {
     async fn message(&mut self, message: goval::Command, session: i32) -> Result<()> {
-        if let Some(mut msg) = self
+        let reply = match self
             ._inner
             .message(&self.info, message.clone(), session)
-            .await?
+            .await
         {
+            Ok(reply) => reply,
+            // Let the client know instead of leaving it waiting for a reply
+            Err(err) if err.is::<PathEscape>() => {
+                warn!(%err, session, "Rejected path outside of the workspace");
+                Some(goval::Command {
+                    body: Some(goval::command::Body::Error(err.to_string())),
+                    ..Default::default()
+                })
+            }
+            Err(err) => return Err(err),
+        };
+
+        if let Some(mut msg) = reply {
             msg.r#ref = message.r#ref;
             self.info.send(msg, SendSessions::Only(session)).await?
         }
@@ -150,19 +183,3 @@ impl Channel {
         Ok(())
     }
 }
-
-pub static IMPLEMENTED_SERVICES: &[&str] = &[
-    "chat",
-    "gcsfiles",
-    "presence",
-    "ot",
-    "snapshot",
-    "null",
-    "git",
-    "open",
-    "output",
-    "shell",
-    "toolchain",
-    "dotreplit",
-    "exec",
-];
@@ -0,0 +1,60 @@
+use std::{collections::HashMap, sync::LazyLock};
+use tokio::sync::Mutex;
+use tracing::debug;
+
+// Advisory locks taken through `fsTakeLock`, shared between every gcsfiles
+// channel. They don't stop anything from touching the files, clients are
+// expected to check them.
+static LOCKS: LazyLock<Mutex<HashMap<String, Holder>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+struct Holder {
+    channel: i32,
+    session: i32,
+}
+
+/// Takes `name` for `session`, returns false if another session holds it.
+/// Taking it again keeps it tied to the channel it was first taken through.
+pub async fn take(name: &str, channel: i32, session: i32) -> bool {
+    let mut locks = LOCKS.lock().await;
+
+    match locks.get(name) {
+        Some(current) => current.session == session,
+        None => {
+            debug!(name, session, "Took fs lock");
+            locks.insert(name.to_string(), Holder { channel, session });
+            true
+        }
+    }
+}
+
+/// Releases `name` if `session` holds it, returns false otherwise
+pub async fn release(name: &str, session: i32) -> bool {
+    let mut locks = LOCKS.lock().await;
+
+    match locks.get(name) {
+        Some(current) if current.session == session => {
+            debug!(name, session, "Released fs lock");
+            locks.remove(name);
+            true
+        }
+        _ => false,
+    }
+}
+
+/// Releases every lock `session` took through `channel`
+pub async fn release_session(channel: i32, session: i32) {
+    LOCKS
+        .lock()
+        .await
+        .retain(|_, holder| *holder != Holder { channel, session });
+}
+
+/// Releases every lock taken through `channel`
+pub async fn release_channel(channel: i32) {
+    LOCKS
+        .lock()
+        .await
+        .retain(|_, holder| holder.channel != channel);
+}
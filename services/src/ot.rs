@@ -1,25 +1,21 @@
 pub struct OT {
-    crc32: u32,
-    version: u32,
-    contents: ropey::Rope,
-    path: String,
-    cursors: HashMap<String, goval::OtCursor>,
-    history: Vec<goval::OtPacket>,
+    document: Option<SharedDocument>,
+    channel: i32,
+    // The path the watcher is following, catches up when the document is renamed
+    watched: String,
     watcher: FSWatcher,
 }
 
-use std::{
-    collections::HashMap,
-    time::{Duration, SystemTime, UNIX_EPOCH},
+use crate::{
+    client::ClientInfo,
+    fs_watcher::FSWatcher,
+    ot_document::{self, now, SharedDocument},
+    ot_transform, paths, secrets, FSEvent, IPCMessage,
 };
 
-use crate::{client::ClientInfo, fs_watcher::FSWatcher, FSEvent, IPCMessage};
-
 use super::traits;
 use anyhow::{format_err, Result};
 use async_trait::async_trait;
-use similar::TextDiff;
-use tokio::fs;
 use tracing::{debug, error, trace, warn};
 
 impl OT {
@@ -29,12 +25,9 @@ impl OT {
         let watcher = FSWatcher::new(sender).await?;
 
         let chan = OT {
-            crc32: 0,
-            version: 1,
-            contents: "".into(),
-            path: "".to_string(),
-            cursors: HashMap::new(),
-            history: vec![],
+            document: None,
+            channel: 0,
+            watched: "".to_string(),
             watcher,
         };
 
@@ -59,206 +52,187 @@ impl traits::Service for OT {
             Some(body) => body,
         };
 
-        if self.path.is_empty() {
-            if let goval::command::Body::OtLinkFile(link_file) = body.clone() {
-                let path = link_file.file.unwrap().path;
-                if (fs::metadata(path.clone()).await).is_err() {
-                    let error = goval::Command {
-                        body: Some(goval::command::Body::Error(format!(
-                            "{}: no such file or directory",
-                            path
-                        ))),
-                        ..Default::default()
-                    };
-                    return Ok(Some(error));
-                }
-
-                self.path = path.clone();
-                let byte_contents = fs::read(path.clone()).await?;
-                let crc32 = crc32fast::hash(byte_contents.as_slice());
-
-                self.crc32 = crc32;
-
-                let file_contents = String::from_utf8(byte_contents.clone())?;
+        let document = match &self.document {
+            Some(document) => document.clone(),
+            None => {
+                if let goval::command::Body::OtLinkFile(link_file) = body {
+                    let resolved = paths::resolve(&link_file.file.unwrap().path)?;
+                    if secrets::is_secret(&resolved) {
+                        return Ok(Some(goval::Command {
+                            body: Some(goval::command::Body::Error(format!(
+                                "{}: kept in the secret store, can't be linked",
+                                resolved.relative
+                            ))),
+                            ..Default::default()
+                        }));
+                    }
+                    let path = resolved.relative;
+
+                    let document =
+                        match ot_document::open(&path, info.id, info.sender.clone()).await {
+                            Ok(document) => document,
+                            Err(err) => {
+                                return Ok(Some(goval::Command {
+                                    body: Some(goval::command::Body::Error(err.to_string())),
+                                    ..Default::default()
+                                }))
+                            }
+                        };
 
-                self.contents = file_contents.clone().into();
+                    self.document = Some(document.clone());
+                    self.channel = info.id;
+                    self.watched = path.clone();
+                    self.watcher.watch(vec![path.clone()]).await?;
 
-                let timestamp = Some(prost_types::Timestamp {
-                    seconds: SystemTime::now()
-                        .duration_since(UNIX_EPOCH)
-                        .unwrap()
-                        .as_secs() as i64,
-                    nanos: 0,
-                });
+                    let document = document.lock().await;
+                    let file = goval::File {
+                        path,
+                        content: document.contents.to_string().into_bytes(),
+                        ..Default::default()
+                    };
 
-                let hist_item = goval::OtPacket {
-                    spooky_version: self.version,
-                    version: self.version,
-                    op: vec![goval::OtOpComponent {
-                        op_component: Some(goval::ot_op_component::OpComponent::Insert(
-                            file_contents,
+                    let link_response = goval::Command {
+                        body: Some(goval::command::Body::OtLinkFileResponse(
+                            goval::OtLinkFileResponse {
+                                version: document.version,
+                                linked_file: Some(file),
+                            },
                         )),
-                    }],
-                    crc32,
-                    committed: timestamp,
-                    author: goval::ot_packet::Author::User.into(),
-                    user_id: 23352071,
-                    nonce: 0,
-                };
-
-                self.history.push(hist_item);
-
-                let mut link_response = goval::Command::default();
-
-                let file = goval::File {
-                    path: path.clone(),
-                    content: byte_contents,
-                    ..Default::default()
-                };
-
-                let _inner = goval::OtLinkFileResponse {
-                    version: self.version,
-                    linked_file: Some(file),
-                };
-                link_response.body = Some(goval::command::Body::OtLinkFileResponse(_inner));
-
-                self.watcher.watch(vec![path]).await?;
-
-                // let mut reader = self.watcher.get_event_reader().await;
-                // let sending_map = self._sending_map.clone();
-                // let file_path = self.path.clone();
-                // let crc32 = self.crc32.clone();
-                // let contents = self.contents.clone();
-                // let version = self.version.clone();
-                // let history = self.history.clone();
-                // let channel_id = info.id.clone();
-                // tokio::spawn(async move {
-                //     loop {
-                //         let res = async {
-                //             match reader.recv().await {
-                //                 Ok(res) => {
-
-                //                     LoopControl::Cont(Ok(()))
-                //                 }
-                //                 Err(err) => match err {
-                //                     RecvError::Closed => LoopControl::Break,
-                //                     RecvError::Lagged(ammount) => {
-                //                         warn!(messages = ammount; "FSEvents lagged");
-                //                         LoopControl::Cont(Ok(()))
-                //                     }
-                //                 },
-                //             }
-                //         }
-                //         .await;
-
-                //         match res {
-                //             LoopControl::Break => break,
-                //             LoopControl::Cont(result) => result.expect("TODO: deal with this"),
-                //         }
-                //     }
-                // });
-
-                return Ok(Some(link_response));
-            } else {
-                return Err(format_err!("Command sent before otLinkFile"));
+                        ..Default::default()
+                    };
+                    return Ok(Some(link_response));
+                } else {
+                    return Err(format_err!("Command sent before otLinkFile"));
+                }
             }
-        }
+        };
+        let mut document = document.lock().await;
 
         match body {
             goval::command::Body::Ot(ot) => {
-                let mut cursor: usize = 0;
-
-                for op in ot.op.clone() {
-                    match op.op_component.unwrap() {
-                        goval::ot_op_component::OpComponent::Skip(_skip) => {
-                            let skip: usize = _skip.try_into()?;
-                            if skip + cursor > self.contents.len_chars() {
-                                let err = goval::Command {
-                                    body: Some(goval::command::Body::Error(
-                                        "Invalid skip past bounds".to_string(),
-                                    )),
-                                    ..Default::default()
-                                };
-                                return Ok(Some(err));
-                            }
+                // Catch up on a removal the watcher missed first, so the file
+                // is recreated below
+                if document.writer.gone() && !document.deleted {
+                    document.reload().await?;
+                }
 
-                            cursor += skip
-                        }
-                        goval::ot_op_component::OpComponent::Delete(_delete) => {
-                            let delete: usize = _delete.try_into()?;
-                            if delete + cursor > self.contents.len_chars() {
-                                let err = goval::Command {
-                                    body: Some(goval::command::Body::Error(
-                                        "Invalid delete past bounds".to_string(),
-                                    )),
-                                    ..Default::default()
-                                };
-                                return Ok(Some(err));
-                            }
+                // Packets carry the version they'd create, so they were made
+                // against the one before it
+                let base = ot.version.saturating_sub(1);
 
-                            self.contents.remove(cursor..(cursor + delete))
-                        }
-                        goval::ot_op_component::OpComponent::Insert(insert) => {
-                            self.contents.insert(cursor, &insert)
-                        }
-                    }
+                if base > document.version {
+                    return Ok(Some(goval::Command {
+                        body: Some(goval::command::Body::Error(format!(
+                            "Packet is based on version {} but the document is only at {}",
+                            base, document.version
+                        ))),
+                        ..Default::default()
+                    }));
                 }
 
-                let to_write = self.contents.to_string();
-                self.version += 1;
-                // drop(version);
-
-                let user_id;
-                if ot.author == goval::ot_packet::Author::Ghostwriter as i32 {
-                    user_id = 22261053 // https://replit.com/@ghostwriterai
-                } else if let Some(user) = info.sessions.get(&session) {
-                    user_id = user.id
-                } else {
-                    user_id = 23054564 // https://replit.com/@homeval-user
+                if base < document.oldest_base() {
+                    return Ok(Some(goval::Command {
+                        body: Some(goval::command::Body::Error(format!(
+                            "Packet is based on version {} which is too old to transform",
+                            base
+                        ))),
+                        ..Default::default()
+                    }));
                 }
 
+                // Rebase over everything committed since the client's version
+                let mut op = ot_transform::from_proto(&ot.op);
+                for packet in document
+                    .history
+                    .iter()
+                    .filter(|packet| packet.version > base)
+                {
+                    op = ot_transform::transform(&op, &ot_transform::from_proto(&packet.op));
+                }
+                if base != document.version {
+                    debug!(base, version = document.version, "Transformed OT packet");
+                }
+                let components = ot_transform::to_proto(&op);
+
+                // Nothing is touched until the whole op is known to apply
+                let contents = match apply(&document.contents, &op) {
+                    Ok(contents) => contents,
+                    Err(err) => {
+                        return Ok(Some(goval::Command {
+                            body: Some(goval::command::Body::Error(err.to_string())),
+                            ..Default::default()
+                        }))
+                    }
+                };
+                let to_write = contents.to_string();
                 let crc32 = crc32fast::hash(to_write.as_bytes());
-                self.crc32 = crc32;
 
-                let committed = Some(prost_types::Timestamp {
-                    seconds: SystemTime::now()
-                        .duration_since(UNIX_EPOCH)
-                        .unwrap()
-                        .as_secs() as i64,
-                    nanos: 0,
-                });
+                // A rebased packet can't know the result's crc, so only
+                // packets made against the latest version are checked
+                if ot.crc32 != 0 && base == document.version && ot.crc32 != crc32 {
+                    warn!(
+                        expected = ot.crc32,
+                        actual = crc32,
+                        path = document.path,
+                        "OT packet crc32 mismatch, resyncing client"
+                    );
+                    return Ok(Some(goval::Command {
+                        body: Some(goval::command::Body::Otstatus(
+                            document.status(self.channel),
+                        )),
+                        ..Default::default()
+                    }));
+                }
+
+                document.contents = contents;
+                document.version += 1;
+                // drop(version);
+
+                let (author, user_id) = author(info.sessions.get(&session), ot.author);
+
+                document.crc32 = crc32;
 
                 let packet = goval::OtPacket {
-                    spooky_version: self.version,
-                    version: self.version,
-                    op: ot.op,
-                    committed,
+                    spooky_version: document.version,
+                    version: document.version,
+                    op: components,
+                    committed: now(),
                     crc32,
-                    nonce: 0,
+                    // Lets the author recognise its edit when it's broadcast
+                    nonce: ot.nonce,
                     user_id,
-                    author: ot.author,
+                    author: author.into(),
+                    session,
                 };
 
-                self.history.push(packet.clone());
+                document.commit(packet.clone());
 
                 let ot_notif = goval::Command {
-                    body: Some(goval::command::Body::Ot(packet)),
+                    body: Some(goval::command::Body::Ot(packet.clone())),
                     ..Default::default()
                 };
+                document.broadcast(ot_notif, Some((self.channel, session)));
+
+                if document.deleted {
+                    debug!(path = document.path, "Recreating removed linked file");
+                    document.deleted = false;
+                    document
+                        .writer
+                        .recreate(&document.path, document.contents.clone());
+                } else {
+                    document
+                        .writer
+                        .write(&document.path, document.contents.clone());
+                }
 
-                info.send(ot_notif, crate::SendSessions::Everyone).await?;
-
-                fs::write(&self.path, to_write).await?;
-
-                let ok = goval::Command {
-                    body: Some(goval::command::Body::Ok(goval::Ok {})),
+                // The author gets the packet as it was actually applied
+                Ok(Some(goval::Command {
+                    body: Some(goval::command::Body::Ot(packet)),
                     ..Default::default()
-                };
-
-                Ok(Some(ok))
+                }))
             }
             goval::command::Body::OtNewCursor(cursor) => {
-                self.cursors.insert(cursor.id.clone(), cursor.clone());
+                document.set_cursor(self.channel, session, cursor.clone());
 
                 let cursor_notif = goval::Command {
                     body: Some(goval::command::Body::OtNewCursor(cursor)),
@@ -270,7 +244,7 @@ impl traits::Service for OT {
                 Ok(None)
             }
             goval::command::Body::OtDeleteCursor(cursor) => {
-                self.cursors.remove(&cursor.id);
+                document.delete_cursor(self.channel, &cursor.id);
 
                 let cursor_delete_notif = goval::Command {
                     body: Some(goval::command::Body::OtDeleteCursor(cursor)),
@@ -286,14 +260,31 @@ impl traits::Service for OT {
                 Ok(None)
             }
             goval::command::Body::OtFetchRequest(request) => {
-                let mut packets: Vec<goval::OtPacket> = vec![];
-                let from = (request.version_from - 1) as usize;
-                let to = request.version_to as usize;
-                for (index, item) in self.history.iter().enumerate() {
-                    if index >= from && index <= to {
-                        packets.push(item.clone())
+                let start = document
+                    .history
+                    .partition_point(|packet| packet.version < request.version_from);
+                let mut end = document
+                    .history
+                    .partition_point(|packet| packet.version <= request.version_to);
+                // Versions that were compacted away are answered with the
+                // checkpoint that replaced them, as long as it's in range
+                if let Some(first) = document
+                    .history
+                    .first()
+                    .filter(|first| first.version > 1 && request.version_from < first.version)
+                {
+                    if first.version > request.version_to {
+                        return Ok(Some(goval::Command {
+                            body: Some(goval::command::Body::Error(format!(
+                                "Versions {} to {} were compacted into version {}",
+                                request.version_from, request.version_to, first.version
+                            ))),
+                            ..Default::default()
+                        }));
                     }
+                    end = end.max(1);
                 }
+                let packets = document.history[start..end.max(start)].to_vec();
 
                 let mut history_result = goval::Command::default();
                 let _inner = goval::OtFetchResponse { packets };
@@ -301,7 +292,70 @@ impl traits::Service for OT {
 
                 Ok(Some(history_result))
             }
+            goval::command::Body::OtBlameRequest(_) => Ok(Some(goval::Command {
+                body: Some(goval::command::Body::OtBlameResponse(document.blame())),
+                ..Default::default()
+            })),
+            goval::command::Body::OtTransformSelectionRequest(request) => {
+                let to = if request.version_to == 0 {
+                    document.version
+                } else {
+                    request.version_to
+                };
+                if request.version_from > to
+                    || to > document.version
+                    || request.version_from < document.oldest_base()
+                {
+                    return Ok(Some(goval::Command {
+                        body: Some(goval::command::Body::Error(format!(
+                            "Can't transform from version {} to {}",
+                            request.version_from, to
+                        ))),
+                        ..Default::default()
+                    }));
+                }
+
+                let mut start = request.index_start as usize;
+                let mut end = request.index_end as usize;
+                for packet in document
+                    .history
+                    .iter()
+                    .filter(|packet| packet.version > request.version_from && packet.version <= to)
+                {
+                    let op = ot_transform::from_proto(&packet.op);
+                    start = ot_transform::transform_index(start, &op);
+                    end = ot_transform::transform_index(end, &op);
+                }
+
+                Ok(Some(goval::Command {
+                    body: Some(goval::command::Body::OtTransformSelectionResponse(
+                        goval::OtTransformSelectionResponse {
+                            index_start: start as u32,
+                            index_end: end as u32,
+                            version: to,
+                        },
+                    )),
+                    ..Default::default()
+                }))
+            }
             goval::command::Body::Flush(_) => {
+                if document.writer.gone() && !document.deleted {
+                    document.reload().await?;
+                }
+
+                let flushed = if document.deleted {
+                    Err(format_err!("{}: no such file or directory", document.path))
+                } else {
+                    document.writer.flush().await
+                };
+                if let Err(err) = flushed {
+                    warn!(%err, path = document.path, "Error flushing OT document");
+                    return Ok(Some(goval::Command {
+                        body: Some(goval::command::Body::Error(err.to_string())),
+                        ..Default::default()
+                    }));
+                }
+
                 let ok = goval::Command {
                     body: Some(goval::command::Body::Ok(goval::Ok {})),
                     ..Default::default()
@@ -315,73 +369,59 @@ impl traits::Service for OT {
         }
     }
 
-    async fn fsevent(&mut self, info: &super::types::ChannelInfo, event: FSEvent) -> Result<()> {
-        trace!(?event, file_path = self.path, "fs event");
-        match event {
-            FSEvent::Modify(path) => {
-                trace!(
-                    condition = (path == self.path),
-                    path = path,
-                    file_path = self.path,
-                    "Conditional time"
-                );
-                if path == self.path {
-                    let new_contents = fs::read(&path).await?;
-
-                    let new_crc32 = crc32fast::hash(&new_contents);
-                    if new_crc32 == self.crc32 {
-                        return Ok(());
-                    }
-
-                    self.version += 1;
-
-                    let new_contents =
-                        String::from_utf8(new_contents).expect("TODO: Deal with this");
-
-                    let ops = diff(self.contents.to_string(), new_contents.clone());
-
-                    self.contents = new_contents.into();
-                    self.crc32 = new_crc32;
-
-                    let committed = Some(prost_types::Timestamp {
-                        seconds: SystemTime::now()
-                            .duration_since(UNIX_EPOCH)
-                            .unwrap()
-                            .as_secs() as i64,
-                        nanos: 0,
-                    });
-
-                    let packet = goval::OtPacket {
-                        spooky_version: self.version,
-                        version: self.version,
-                        op: ops,
-                        committed,
-                        crc32: new_crc32,
-                        nonce: 0,
-                        user_id: 0,
-                        author: goval::ot_packet::Author::User.into(),
-                    };
-
-                    self.history.push(packet.clone());
-
-                    let ot_notif = goval::Command {
-                        body: Some(goval::command::Body::Ot(packet)),
-                        ..Default::default()
-                    };
+    async fn fsevent(&mut self, _info: &super::types::ChannelInfo, event: FSEvent) -> Result<()> {
+        let document = match &self.document {
+            Some(document) => document.clone(),
+            None => return Ok(()),
+        };
 
-                    info.send(ot_notif, crate::SendSessions::Everyone).await?;
+        let mut moved = None;
+        {
+            let mut locked = document.lock().await;
+            trace!(?event, file_path = locked.path, "fs event");
+
+            // Every linked channel gets the same events, whichever sees one
+            // first updates the document and the rest find nothing changed
+            match event {
+                // Events might have been dropped, check the file ourselves
+                FSEvent::Rescan => locked.reload().await?,
+                FSEvent::Modify(path) | FSEvent::Create(path) if path == locked.path => {
+                    locked.reload().await?
                 }
-                Ok(())
-            }
-            FSEvent::Err(err) => {
-                error!(err, "Error in FS event listener");
-                Ok(())
+                // Something else was moved on top of the file
+                FSEvent::Rename(_, to) if to == locked.path => locked.reload().await?,
+                FSEvent::Rename(from, to) if from == locked.path => moved = Some((from, to)),
+                FSEvent::Remove(path) if path == locked.path => locked.tombstone(),
+                FSEvent::Err(err) => error!(err, "Error in FS event listener"),
+                event => debug!(message = ?event, "Ignoing FS event"),
             }
-            _ => {
-                debug!(message = ?event, "Ignoing FS event");
-                Ok(())
+        }
+
+        // The registry is locked before documents, not while holding one
+        if let Some((from, to)) = moved {
+            let renamed = ot_document::rename(&document, &from, &to).await;
+            let mut locked = document.lock().await;
+
+            // Another linked channel might have caught up with it already.
+            // The document already open there keeps the file, this one is
+            // written back under its old name on the next edit.
+            if locked.path == from {
+                if renamed {
+                    locked.follow(to);
+                } else {
+                    locked.tombstone();
+                }
             }
         }
+
+        let path = document.lock().await.path.clone();
+        if path != self.watched {
+            let from = std::mem::replace(&mut self.watched, path.clone());
+            self.watcher.unwatch(&[from]).await?;
+            self.watcher.watch(vec![path]).await?;
+        }
+
+        Ok(())
     }
 
     async fn attach(
@@ -391,108 +431,140 @@ impl traits::Service for OT {
         _session: i32,
         _sender: tokio::sync::mpsc::UnboundedSender<IPCMessage>,
     ) -> Result<Option<goval::Command>> {
-        if self.path.is_empty() {
-            let cmd = goval::Command {
-                body: Some(goval::command::Body::Otstatus(goval::OtStatus::default())),
-                ..Default::default()
-            };
-            return Ok(Some(cmd));
-        }
-        let mut status = goval::Command::default();
-
-        let file = goval::File {
-            path: self.path.clone(),
-            ..Default::default()
+        let status = match &self.document {
+            Some(document) => document.lock().await.status(self.channel),
+            None => goval::OtStatus::default(),
         };
 
-        let mut cursors = vec![];
+        Ok(Some(goval::Command {
+            body: Some(goval::command::Body::Otstatus(status)),
+            ..Default::default()
+        }))
+    }
 
-        for cursor in self.cursors.values() {
-            cursors.push(cursor.clone())
+    async fn detach(&mut self, _info: &super::types::ChannelInfo, session: i32) -> Result<()> {
+        if let Some(document) = &self.document {
+            document.lock().await.leave(self.channel, session);
         }
-
-        let _inner = goval::OtStatus {
-            contents: self.contents.to_string(),
-            version: self.version,
-            linked_file: Some(file),
-            cursors,
-        };
-
-        status.body = Some(goval::command::Body::Otstatus(_inner));
-
-        Ok(Some(status))
+        Ok(())
     }
 
     async fn shutdown(self: Box<OT>, _info: &super::types::ChannelInfo) -> Result<()> {
+        if let Some(document) = &self.document {
+            ot_document::close(document, self.channel).await;
+        }
         self.watcher.shutdown().await;
         Ok(())
     }
 }
 
-fn diff(old_text: String, new_text: String) -> Vec<goval::OtOpComponent> {
-    let mut _differ = TextDiff::configure();
-    let differ = _differ.timeout(Duration::from_secs(1));
-    let diff = differ.diff_chars(&old_text, &new_text);
-
-    let mut parts: Vec<goval::OtOpComponent> = vec![];
-    let mut last_op: Option<goval::ot_op_component::OpComponent> = None;
-    for part in diff.iter_all_changes() {
-        let mut new_op: Option<goval::ot_op_component::OpComponent> = None;
-        match part.tag() {
-            similar::ChangeTag::Equal => {
-                if let Some(goval::ot_op_component::OpComponent::Skip(amount)) = last_op.clone() {
-                    last_op = Some(goval::ot_op_component::OpComponent::Skip(
-                        amount + part.value().len() as u32,
-                    ))
-                } else {
-                    new_op = Some(goval::ot_op_component::OpComponent::Skip(
-                        part.value().len() as u32,
-                    ));
+// Who a client's edit is attributed to. Only the server makes system edits,
+// and only a verified ghostwriter session can author as ghostwriter, anyone
+// else claiming either identity is attributed to the default user.
+fn author(client: Option<&ClientInfo>, requested: i32) -> (goval::ot_packet::Author, u32) {
+    let fallback = ClientInfo::default().id;
+    let client = match client {
+        Some(client) => client,
+        None => return (goval::ot_packet::Author::User, fallback),
+    };
+
+    let ghostwriter = client.is_secure && client.id == *ot_document::GHOSTWRITER_USER_ID;
+    if ghostwriter && requested == goval::ot_packet::Author::Ghostwriter as i32 {
+        return (goval::ot_packet::Author::Ghostwriter, client.id);
+    }
+
+    let user_id = if client.id == *ot_document::SYSTEM_USER_ID
+        || (client.id == *ot_document::GHOSTWRITER_USER_ID && !ghostwriter)
+    {
+        fallback
+    } else {
+        client.id
+    };
+    (goval::ot_packet::Author::User, user_id)
+}
+
+// Applies an op to a copy of the contents, so a bad op leaves the original alone
+fn apply(contents: &ropey::Rope, op: &ot_transform::Op) -> Result<ropey::Rope> {
+    let mut contents = contents.clone();
+    let mut cursor: usize = 0;
+
+    for component in op {
+        match component {
+            ot_transform::Component::Skip(skip) => {
+                if cursor + skip > contents.len_chars() {
+                    return Err(format_err!("Invalid skip past bounds"));
                 }
+                cursor += skip
             }
-            similar::ChangeTag::Delete => {
-                if let Some(goval::ot_op_component::OpComponent::Delete(amount)) = last_op.clone() {
-                    last_op = Some(goval::ot_op_component::OpComponent::Delete(
-                        amount + part.value().len() as u32,
-                    ))
-                } else {
-                    new_op = Some(goval::ot_op_component::OpComponent::Delete(
-                        part.value().len() as u32,
-                    ));
+            ot_transform::Component::Delete(delete) => {
+                if cursor + delete > contents.len_chars() {
+                    return Err(format_err!("Invalid delete past bounds"));
                 }
+                contents.remove(cursor..(cursor + delete))
             }
-            similar::ChangeTag::Insert => {
-                if let Some(goval::ot_op_component::OpComponent::Insert(same)) = last_op.clone() {
-                    last_op = Some(goval::ot_op_component::OpComponent::Insert(
-                        same + part.value(),
-                    ))
-                } else {
-                    new_op = Some(goval::ot_op_component::OpComponent::Insert(
-                        part.value().to_string(),
-                    ));
-                }
+            ot_transform::Component::Insert(insert) => {
+                contents.insert(cursor, insert);
+                cursor += insert.chars().count()
             }
         }
+    }
 
-        if let Some(new_part) = new_op {
-            if let Some(last_part) = last_op.clone() {
-                parts.push(goval::OtOpComponent {
-                    op_component: Some(last_part),
-                });
-            }
+    Ok(contents)
+}
 
-            last_op = Some(new_part);
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use goval::ot_packet::Author;
+
+    fn client(id: u32, is_secure: bool) -> ClientInfo {
+        ClientInfo {
+            is_secure,
+            id,
+            ..Default::default()
         }
     }
 
-    if let Some(op) = last_op {
-        match op {
-            goval::ot_op_component::OpComponent::Skip(_) => {}
-            _ => parts.push(goval::OtOpComponent {
-                op_component: Some(op),
-            }),
-        }
+    #[test]
+    fn users_author_as_themselves() {
+        let user = client(1234, false);
+        assert_eq!(
+            author(Some(&user), Author::User as i32),
+            (Author::User, 1234)
+        );
+        assert_eq!(
+            author(Some(&user), Author::Ghostwriter as i32),
+            (Author::User, 1234)
+        );
+        assert_eq!(
+            author(Some(&user), Author::System as i32),
+            (Author::User, 1234)
+        );
+    }
+
+    #[test]
+    fn only_verified_ghostwriter_authors_as_ghostwriter() {
+        let id = *ot_document::GHOSTWRITER_USER_ID;
+        assert_eq!(
+            author(Some(&client(id, true)), Author::Ghostwriter as i32),
+            (Author::Ghostwriter, id)
+        );
+        assert_eq!(
+            author(Some(&client(id, false)), Author::Ghostwriter as i32),
+            (Author::User, ClientInfo::default().id)
+        );
     }
 
-    parts
+    #[test]
+    fn nobody_authors_as_the_system() {
+        let system = client(*ot_document::SYSTEM_USER_ID, true);
+        assert_eq!(
+            author(Some(&system), Author::System as i32),
+            (Author::User, ClientInfo::default().id)
+        );
+        assert_eq!(
+            author(None, Author::System as i32),
+            (Author::User, ClientInfo::default().id)
+        );
+    }
 }
@@ -0,0 +1,151 @@
+//! Which edit last touched each part of an OT document. Kept up to date as
+//! packets are committed and stored with each checkpoint, so it outlives the
+//! history that's compacted into it.
+
+use crate::ot_transform::{Component, Op};
+
+/// Who made an edit, taken from its packet
+#[derive(Clone, Debug)]
+pub struct Edit {
+    pub user_id: u32,
+    pub session: i32,
+    pub author: i32,
+    pub version: u32,
+}
+
+impl Edit {
+    pub fn of(packet: &goval::OtPacket) -> Edit {
+        Edit {
+            user_id: packet.user_id,
+            session: packet.session,
+            author: packet.author,
+            version: packet.version,
+        }
+    }
+
+    // Neighbouring text from the same person is reported as one range, with
+    // the newest version either part was touched in
+    fn same_person(&self, other: &Edit) -> bool {
+        self.user_id == other.user_id
+            && self.session == other.session
+            && self.author == other.author
+    }
+}
+
+#[derive(Clone, Debug)]
+struct Span {
+    // In chars
+    len: usize,
+    edit: Edit,
+}
+
+#[derive(Clone, Default)]
+pub struct Blame {
+    spans: Vec<Span>,
+}
+
+impl Blame {
+    pub fn apply(&mut self, op: &Op, edit: &Edit) {
+        let mut old = std::mem::take(&mut self.spans).into_iter();
+        let mut current = old.next();
+
+        for component in op {
+            match component {
+                Component::Skip(count) => self.take(&mut current, &mut old, *count, true),
+                Component::Delete(count) => self.take(&mut current, &mut old, *count, false),
+                Component::Insert(text) => self.push(Span {
+                    len: text.chars().count(),
+                    edit: edit.clone(),
+                }),
+            }
+        }
+
+        // Anything after the last component is left untouched
+        for span in current.into_iter().chain(old) {
+            self.push(span);
+        }
+    }
+
+    // Moves `count` chars of the old spans over, or drops them
+    fn take(
+        &mut self,
+        current: &mut Option<Span>,
+        old: &mut std::vec::IntoIter<Span>,
+        mut count: usize,
+        keep: bool,
+    ) {
+        while count > 0 {
+            let span = match current.as_mut() {
+                Some(span) => span,
+                None => return,
+            };
+
+            let taken = count.min(span.len);
+            if keep {
+                self.push(Span {
+                    len: taken,
+                    edit: span.edit.clone(),
+                });
+            }
+            span.len -= taken;
+            count -= taken;
+
+            if span.len == 0 {
+                *current = old.next();
+            }
+        }
+    }
+
+    fn push(&mut self, span: Span) {
+        if span.len == 0 {
+            return;
+        }
+        match self.spans.last_mut() {
+            Some(last) if last.edit.same_person(&span.edit) => {
+                last.len += span.len;
+                last.edit.version = last.edit.version.max(span.edit.version);
+            }
+            _ => self.spans.push(span),
+        }
+    }
+
+    pub fn from_proto(ranges: &[goval::OtBlameRange]) -> Blame {
+        let mut blame = Blame::default();
+        for range in ranges {
+            blame.push(Span {
+                len: range.index_end.saturating_sub(range.index_start) as usize,
+                edit: Edit {
+                    user_id: range.user_id,
+                    session: range.session,
+                    author: range.author,
+                    version: range.version,
+                },
+            });
+        }
+        blame
+    }
+
+    pub fn to_proto(&self) -> Vec<goval::OtBlameRange> {
+        let mut start = 0;
+        self.spans
+            .iter()
+            .map(|span| {
+                let range = goval::OtBlameRange {
+                    index_start: start as u32,
+                    index_end: (start + span.len) as u32,
+                    user_id: span.edit.user_id,
+                    session: span.edit.session,
+                    author: span.edit.author,
+                    version: span.edit.version,
+                };
+                start += span.len;
+                range
+            })
+            .collect()
+    }
+
+    /// Roughly how much memory it's using, in bytes
+    pub fn size(&self) -> usize {
+        self.spans.len() * std::mem::size_of::<Span>()
+    }
+}
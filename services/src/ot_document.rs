@@ -0,0 +1,854 @@
+//! OT documents, one per path no matter how many `ot` channels have it
+//! linked. The contents, version and history are shared, cursors are kept per
+//! channel.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, LazyLock, Weak},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{format_err, Result};
+use prost::Message;
+use serde::Serialize;
+use similar::TextDiff;
+use tokio::{fs, sync::Mutex, time::Instant};
+use tracing::{debug, warn};
+
+use crate::{
+    ot_blame::{self, Blame},
+    ot_persist, ot_transform,
+    ot_writer::Writer,
+    ChannelMessage, SendSessions,
+};
+
+pub type SharedDocument = Arc<Mutex<Document>>;
+
+// Lock this before a document when both are needed
+static DOCUMENTS: LazyLock<Mutex<HashMap<String, SharedDocument>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+// Held while a path is loaded, so it's loaded once without holding DOCUMENTS
+static LOADING: LazyLock<Mutex<HashMap<String, Arc<Mutex<()>>>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+// Versions kept as individual packets
+static HISTORY_WINDOW: LazyLock<u32> = LazyLock::new(|| {
+    std::env::var("HOMEVAL_OT_HISTORY_WINDOW")
+        .ok()
+        .and_then(|window| window.parse().ok())
+        .unwrap_or(500)
+});
+
+// Versions between checkpoints, history is compacted this often
+static CHECKPOINT_INTERVAL: LazyLock<u32> = LazyLock::new(|| {
+    std::env::var("HOMEVAL_OT_CHECKPOINT_INTERVAL")
+        .ok()
+        .and_then(|interval| interval.parse().ok())
+        .filter(|interval| *interval > 0)
+        .unwrap_or(100)
+});
+
+// Cursors that haven't moved for this long are removed, zero keeps them until
+// the session that made them leaves
+static CURSOR_TTL: LazyLock<Duration> = LazyLock::new(|| {
+    Duration::from_secs(
+        std::env::var("HOMEVAL_OT_CURSOR_TTL_SECS")
+            .ok()
+            .and_then(|secs| secs.parse().ok())
+            .unwrap_or(300),
+    )
+});
+
+/// Who edits made outside of OT, like changes to the file on disk, are
+/// attributed to
+pub static SYSTEM_USER_ID: LazyLock<u32> = LazyLock::new(|| {
+    std::env::var("HOMEVAL_OT_SYSTEM_USER_ID")
+        .ok()
+        .and_then(|id| id.parse().ok())
+        .unwrap_or(0)
+});
+
+/// Who packets sent with the ghostwriter author are attributed to
+pub static GHOSTWRITER_USER_ID: LazyLock<u32> = LazyLock::new(|| {
+    std::env::var("HOMEVAL_OT_GHOSTWRITER_USER_ID")
+        .ok()
+        .and_then(|id| id.parse().ok())
+        // https://replit.com/@ghostwriterai
+        .unwrap_or(22261053)
+});
+
+static USAGE: LazyLock<std::sync::Mutex<HashMap<i32, MemoryUsage>>> =
+    LazyLock::new(|| std::sync::Mutex::new(HashMap::new()));
+
+/// How much memory an ot channel's document and history are using, in bytes.
+/// Channels linked to the same file share everything but their cursors.
+#[derive(Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct MemoryUsage {
+    pub channel: i32,
+    pub path: String,
+    pub version: u32,
+    pub contents: usize,
+    pub history: usize,
+    pub history_packets: usize,
+    /// Checkpoints share unchanged text with the contents, so this is an
+    /// upper bound
+    pub checkpoints: usize,
+    pub blame: usize,
+    pub cursors: usize,
+}
+
+/// Memory usage of every linked ot channel
+pub fn memory_usage() -> Vec<MemoryUsage> {
+    let mut usage: Vec<MemoryUsage> = USAGE.lock().unwrap().values().cloned().collect();
+    usage.sort_by_key(|usage| usage.channel);
+    usage
+}
+
+// The full text at a version, once it falls out of the history window the
+// packets up to it are replaced by one inserting all of it
+#[derive(Clone)]
+struct Checkpoint {
+    version: u32,
+    contents: ropey::Rope,
+    crc32: u32,
+    committed: Option<prost_types::Timestamp>,
+    blame: Blame,
+}
+
+struct TrackedCursor {
+    cursor: goval::OtCursor,
+    // The session that made it, it goes away with them
+    session: i32,
+    updated: Instant,
+}
+
+pub struct Document {
+    pub path: String,
+    pub crc32: u32,
+    pub version: u32,
+    pub contents: ropey::Rope,
+    // The file was removed from disk, it's written back on the next edit
+    pub deleted: bool,
+    pub history: Vec<goval::OtPacket>,
+    // Encoded size of `history`, kept up to date instead of summed on demand
+    history_bytes: usize,
+    checkpoints: Vec<Checkpoint>,
+    blame: Blame,
+    /// Cursors of each linked channel
+    cursors: HashMap<i32, HashMap<String, TrackedCursor>>,
+    channels: HashMap<i32, tokio::sync::mpsc::UnboundedSender<ChannelMessage>>,
+    pub writer: Writer,
+}
+
+/// Links `channel` to the document for `path` (relative to the workspace
+/// root), loading it if no other channel has it open
+pub async fn open(
+    path: &str,
+    channel: i32,
+    sender: tokio::sync::mpsc::UnboundedSender<ChannelMessage>,
+) -> Result<SharedDocument> {
+    if let Some(document) = DOCUMENTS.lock().await.get(path) {
+        join(document, channel, sender).await;
+        return Ok(document.clone());
+    }
+
+    let loading = LOADING
+        .lock()
+        .await
+        .entry(path.to_string())
+        .or_default()
+        .clone();
+    let opened = {
+        let _loading = loading.lock().await;
+        load(path, channel, sender).await
+    };
+
+    let mut loads = LOADING.lock().await;
+    // Only the map and us have it, so nobody else is waiting to load it
+    if Arc::strong_count(&loading) == 2 {
+        loads.remove(path);
+    }
+
+    opened
+}
+
+async fn load(
+    path: &str,
+    channel: i32,
+    sender: tokio::sync::mpsc::UnboundedSender<ChannelMessage>,
+) -> Result<SharedDocument> {
+    // Whoever held the lock before might have loaded it already
+    if let Some(document) = DOCUMENTS.lock().await.get(path) {
+        join(document, channel, sender).await;
+        return Ok(document.clone());
+    }
+
+    let document = Arc::new(Mutex::new(Document::load(path).await?));
+    let mut documents = DOCUMENTS.lock().await;
+    documents.insert(path.to_string(), document.clone());
+    if !CURSOR_TTL.is_zero() {
+        tokio::spawn(expire_cursors(Arc::downgrade(&document)));
+    }
+    join(&document, channel, sender).await;
+
+    Ok(document)
+}
+
+// Callers hold DOCUMENTS, so the document can't be closed in the meantime
+async fn join(
+    document: &SharedDocument,
+    channel: i32,
+    sender: tokio::sync::mpsc::UnboundedSender<ChannelMessage>,
+) {
+    let mut locked = document.lock().await;
+    locked.channels.insert(channel, sender);
+    locked.cursors.entry(channel).or_default();
+    debug!(
+        path = locked.path,
+        channel,
+        channels = locked.channels.len(),
+        "Linked OT document"
+    );
+    locked.report_usage();
+}
+
+/// Unlinks `channel`, the last channel out writes anything pending to disk
+pub async fn close(document: &SharedDocument, channel: i32) {
+    let mut documents = DOCUMENTS.lock().await;
+    let mut locked = document.lock().await;
+
+    locked.channels.remove(&channel);
+    locked.cursors.remove(&channel);
+    USAGE.lock().unwrap().remove(&channel);
+
+    if !locked.channels.is_empty() {
+        return;
+    }
+
+    if documents
+        .get(&locked.path)
+        .is_some_and(|registered| Arc::ptr_eq(registered, document))
+    {
+        documents.remove(&locked.path);
+    }
+    drop(documents);
+
+    debug!(path = locked.path, "Closing OT document");
+    if let Err(err) = locked.writer.flush().await {
+        warn!(%err, path = locked.path, "Error flushing OT document");
+    }
+}
+
+// Runs for as long as the document is open
+async fn expire_cursors(document: Weak<Mutex<Document>>) {
+    let mut interval = tokio::time::interval((*CURSOR_TTL / 4).max(Duration::from_secs(1)));
+
+    loop {
+        interval.tick().await;
+        match document.upgrade() {
+            Some(document) => document.lock().await.expire_cursors(),
+            None => break,
+        }
+    }
+}
+
+/// Moves a document in the registry after its file was renamed, returns false
+/// if another document is already open under the new name
+pub async fn rename(document: &SharedDocument, from: &str, to: &str) -> bool {
+    let mut documents = DOCUMENTS.lock().await;
+
+    if documents
+        .get(to)
+        .is_some_and(|registered| !Arc::ptr_eq(registered, document))
+    {
+        warn!(from, to, "File was renamed onto another open OT document");
+        return false;
+    }
+
+    if documents
+        .get(from)
+        .is_some_and(|registered| Arc::ptr_eq(registered, document))
+    {
+        if let Some(document) = documents.remove(from) {
+            documents.insert(to.to_string(), document);
+        }
+    }
+
+    true
+}
+
+impl Document {
+    async fn load(path: &str) -> Result<Document> {
+        if fs::metadata(path).await.is_err() {
+            return Err(format_err!("{}: no such file or directory", path));
+        }
+
+        let byte_contents = fs::read(path).await?;
+        let file_contents = String::from_utf8(byte_contents.clone())
+            .map_err(|_| format_err!("{}: not a text file", path))?;
+        let crc32 = crc32fast::hash(byte_contents.as_slice());
+
+        let mut document = Document {
+            path: path.to_string(),
+            crc32,
+            version: 1,
+            contents: file_contents.clone().into(),
+            deleted: false,
+            history: vec![],
+            history_bytes: 0,
+            checkpoints: vec![],
+            blame: Blame::default(),
+            cursors: HashMap::new(),
+            channels: HashMap::new(),
+            writer: Writer::new(crc32),
+        };
+
+        let stored = match ot_persist::load(path).await {
+            Ok(stored) => stored,
+            Err(err) => {
+                warn!(%err, path, "Error loading stored OT history, starting fresh");
+                None
+            }
+        };
+
+        match stored {
+            Some(stored) if !stored.history.is_empty() => {
+                document.version = stored
+                    .history
+                    .last()
+                    .map(|packet| packet.version)
+                    .unwrap_or(document.version);
+                document.history = stored.history;
+
+                // Blame for a checkpoint is stored with it, everything after
+                // is rebuilt from the packets
+                let replay = match stored.blame {
+                    Some(blame)
+                        if document
+                            .history
+                            .first()
+                            .is_some_and(|first| first.version == blame.version) =>
+                    {
+                        document.blame = Blame::from_proto(&blame.ranges);
+                        &document.history[1..]
+                    }
+                    _ => &document.history[..],
+                };
+                for packet in replay {
+                    document.blame.apply(
+                        &ot_transform::from_proto(&packet.op),
+                        &ot_blame::Edit::of(packet),
+                    );
+                }
+
+                // The file was changed while nobody had it open, add that as
+                // an edit so history still ends at what's on disk
+                if stored.crc32 != crc32 {
+                    debug!(path, "Reconciling OT history with file on disk");
+                    document.version += 1;
+                    let packet = system_packet(
+                        document.version,
+                        diff(stored.contents, file_contents),
+                        crc32,
+                    );
+                    document.commit(packet);
+                }
+            }
+            _ => {
+                let hist_item = system_packet(
+                    document.version,
+                    vec![goval::OtOpComponent {
+                        op_component: Some(goval::ot_op_component::OpComponent::Insert(
+                            file_contents.clone(),
+                        )),
+                    }],
+                    crc32,
+                );
+
+                document.blame.apply(
+                    &ot_transform::from_proto(&hist_item.op),
+                    &ot_blame::Edit::of(&hist_item),
+                );
+                document.history.push(hist_item);
+                ot_persist::store(
+                    path,
+                    document.contents.clone(),
+                    crc32,
+                    document.history.clone(),
+                    None,
+                );
+            }
+        }
+        document.history_bytes = document
+            .history
+            .iter()
+            .map(|packet| packet.encoded_len())
+            .sum();
+
+        Ok(document)
+    }
+
+    /// Sends `message` to every linked channel, leaving out `except` (a
+    /// channel and session) if given
+    pub fn broadcast(&self, message: goval::Command, except: Option<(i32, i32)>) {
+        for (channel, sender) in &self.channels {
+            let sessions = match except {
+                Some((except_channel, session)) if except_channel == *channel => {
+                    SendSessions::EveryoneExcept(session)
+                }
+                _ => SendSessions::Everyone,
+            };
+            let _ = sender.send(ChannelMessage::ExternalMessage(message.clone(), sessions));
+        }
+    }
+
+    // Like `ChannelInfo::send`, for channels other than the one handling a message
+    fn send(&self, channel: i32, message: goval::Command, sessions: SendSessions) {
+        if let Some(sender) = self.channels.get(&channel) {
+            let _ = sender.send(ChannelMessage::ExternalMessage(message, sessions));
+        }
+    }
+
+    /// Adds or moves a cursor of `channel`
+    pub fn set_cursor(&mut self, channel: i32, session: i32, cursor: goval::OtCursor) {
+        self.cursors.entry(channel).or_default().insert(
+            cursor.id.clone(),
+            TrackedCursor {
+                cursor,
+                session,
+                updated: Instant::now(),
+            },
+        );
+        self.report_usage();
+    }
+
+    pub fn delete_cursor(&mut self, channel: i32, id: &str) {
+        if let Some(cursors) = self.cursors.get_mut(&channel) {
+            cursors.remove(id);
+        }
+        self.report_usage();
+    }
+
+    /// Removes the cursors `session` made in `channel` and tells everyone
+    /// else in it
+    pub fn leave(&mut self, channel: i32, session: i32) {
+        self.remove_cursors(|cursor_channel, tracked| {
+            cursor_channel == channel && tracked.session == session
+        });
+    }
+
+    fn expire_cursors(&mut self) {
+        let now = Instant::now();
+        self.remove_cursors(|_, tracked| now.duration_since(tracked.updated) >= *CURSOR_TTL);
+    }
+
+    fn remove_cursors(&mut self, remove: impl Fn(i32, &TrackedCursor) -> bool) {
+        let mut removed = vec![];
+        for (channel, cursors) in self.cursors.iter_mut() {
+            cursors.retain(|_, tracked| {
+                if remove(*channel, tracked) {
+                    removed.push((*channel, tracked.cursor.clone()));
+                    false
+                } else {
+                    true
+                }
+            });
+        }
+
+        if removed.is_empty() {
+            return;
+        }
+
+        for (channel, cursor) in removed {
+            debug!(
+                path = self.path,
+                channel,
+                id = cursor.id,
+                "Removing OT cursor"
+            );
+            self.send(
+                channel,
+                goval::Command {
+                    body: Some(goval::command::Body::OtDeleteCursor(cursor)),
+                    ..Default::default()
+                },
+                SendSessions::Everyone,
+            );
+        }
+        self.report_usage();
+    }
+
+    /// Adds a packet that's already been applied to the history, and queues
+    /// it for the database if there is one
+    pub fn commit(&mut self, packet: goval::OtPacket) {
+        // Keep everyone's cursors on the text they were on
+        let op = ot_transform::from_proto(&packet.op);
+        for cursor in self
+            .cursors
+            .values_mut()
+            .flat_map(|cursors| cursors.values_mut())
+            .map(|tracked| &mut tracked.cursor)
+        {
+            let transform = |index: u32| ot_transform::transform_index(index as usize, &op) as u32;
+            cursor.position = transform(cursor.position);
+            cursor.selection_start = transform(cursor.selection_start);
+            cursor.selection_end = transform(cursor.selection_end);
+        }
+        self.blame.apply(&op, &ot_blame::Edit::of(&packet));
+
+        ot_persist::append(
+            &self.path,
+            self.contents.clone(),
+            self.crc32,
+            packet.clone(),
+        );
+
+        self.history_bytes += packet.encoded_len();
+        if packet.version % *CHECKPOINT_INTERVAL == 0 {
+            self.checkpoints.push(Checkpoint {
+                version: packet.version,
+                contents: self.contents.clone(),
+                crc32: packet.crc32,
+                committed: packet.committed.clone(),
+                blame: self.blame.clone(),
+            });
+        }
+        self.history.push(packet);
+
+        self.compact();
+        self.report_usage();
+    }
+
+    // Folds everything up to the newest checkpoint that's out of the window
+    // into a single packet
+    fn compact(&mut self) {
+        let index = match self
+            .checkpoints
+            .iter()
+            .rposition(|checkpoint| checkpoint.version + *HISTORY_WINDOW <= self.version)
+        {
+            Some(index) => index,
+            None => return,
+        };
+        let checkpoint = self.checkpoints.drain(..=index).last().unwrap();
+
+        let contents = checkpoint.contents.to_string();
+        // The packet is the system's, who wrote what is stored alongside it
+        let packet = goval::OtPacket {
+            committed: checkpoint.committed,
+            ..system_packet(
+                checkpoint.version,
+                vec![goval::OtOpComponent {
+                    op_component: Some(goval::ot_op_component::OpComponent::Insert(
+                        contents.clone(),
+                    )),
+                }],
+                checkpoint.crc32,
+            )
+        };
+
+        self.history
+            .retain(|packet| packet.version > checkpoint.version);
+        self.history.insert(0, packet);
+        self.history_bytes = self.history.iter().map(|packet| packet.encoded_len()).sum();
+
+        debug!(
+            path = self.path,
+            version = checkpoint.version,
+            packets = self.history.len(),
+            "Compacted OT history"
+        );
+
+        ot_persist::store(
+            &self.path,
+            self.contents.clone(),
+            self.crc32,
+            self.history.clone(),
+            Some(goval::OtBlameResponse {
+                ranges: checkpoint.blame.to_proto(),
+                version: checkpoint.version,
+            }),
+        );
+    }
+
+    /// The oldest version a packet or selection can be transformed from. The
+    /// first packet replaces the whole document, unless it's the very first
+    /// one that can only be based on the empty document before it.
+    pub fn oldest_base(&self) -> u32 {
+        match self.history.first() {
+            Some(packet) if packet.version > 1 => packet.version,
+            _ => 0,
+        }
+    }
+
+    pub fn report_usage(&self) {
+        let checkpoints = self
+            .checkpoints
+            .iter()
+            .map(|checkpoint| checkpoint.contents.len_bytes() + checkpoint.blame.size())
+            .sum();
+
+        let mut usage = USAGE.lock().unwrap();
+        for channel in self.channels.keys() {
+            usage.insert(
+                *channel,
+                MemoryUsage {
+                    channel: *channel,
+                    path: self.path.clone(),
+                    version: self.version,
+                    contents: self.contents.len_bytes(),
+                    history: self.history_bytes,
+                    history_packets: self.history.len(),
+                    checkpoints,
+                    blame: self.blame.size(),
+                    cursors: self.cursors.get(channel).map_or(0, |cursors| cursors.len()),
+                },
+            );
+        }
+    }
+
+    /// Picks up a change made to the file on disk
+    pub async fn reload(&mut self) -> Result<()> {
+        let new_contents = match fs::read(&self.path).await {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                self.tombstone();
+                return Ok(());
+            }
+            Err(err) => return Err(err.into()),
+        };
+
+        if self.deleted {
+            debug!(path = self.path, "Linked file was recreated");
+            self.deleted = false;
+        }
+
+        // Either already up to date or it's our own write, which can be
+        // older than the contents if more edits came in since
+        let new_crc32 = crc32fast::hash(&new_contents);
+        if new_crc32 == self.crc32 || new_crc32 == self.writer.written() {
+            return Ok(());
+        }
+
+        // Most likely caught halfway through a write, the next event will
+        // have the rest. Otherwise it stopped being text and is left alone.
+        let new_contents = match String::from_utf8(new_contents) {
+            Ok(contents) => contents,
+            Err(_) => {
+                warn!(
+                    path = self.path,
+                    "Linked file is not valid utf-8, ignoring change"
+                );
+                return Ok(());
+            }
+        };
+
+        // Changed by something else, which wins over anything not written yet
+        self.writer.cancel();
+        self.writer.set_written(new_crc32);
+        self.version += 1;
+
+        let ops = diff(self.contents.to_string(), new_contents.clone());
+
+        self.contents = new_contents.into();
+        self.crc32 = new_crc32;
+
+        let packet = system_packet(self.version, ops, new_crc32);
+
+        self.commit(packet.clone());
+
+        self.broadcast(
+            goval::Command {
+                body: Some(goval::command::Body::Ot(packet)),
+                ..Default::default()
+            },
+            None,
+        );
+        Ok(())
+    }
+
+    /// The document lives on under the new name, the registry has to be
+    /// updated with [`rename`] first
+    pub fn follow(&mut self, to: String) {
+        let from = std::mem::replace(&mut self.path, to.clone());
+        debug!(from, to, "Linked file was renamed");
+
+        ot_persist::rename(&from, &to);
+        // Anything queued for the old name goes to the new one instead, the
+        // file moved with the latest contents if nothing is
+        if self.crc32 == self.writer.written() {
+            self.writer.cancel();
+        } else {
+            self.writer.write(&to, self.contents.clone());
+        }
+
+        self.broadcast(
+            goval::Command {
+                body: Some(goval::command::Body::FileEvent(goval::FileEvent {
+                    file: Some(goval::File {
+                        path: from,
+                        ..Default::default()
+                    }),
+                    dest: Some(goval::File {
+                        path: to,
+                        ..Default::default()
+                    }),
+                    op: goval::file_event::Op::Move.into(),
+                })),
+                ..Default::default()
+            },
+            None,
+        );
+    }
+
+    /// Keeps the document around so the next edit can write it back
+    pub fn tombstone(&mut self) {
+        if self.deleted {
+            return;
+        }
+
+        debug!(path = self.path, "Linked file was removed");
+        self.deleted = true;
+        self.writer.cancel();
+
+        self.broadcast(
+            goval::Command {
+                body: Some(goval::command::Body::FileEvent(goval::FileEvent {
+                    file: Some(goval::File {
+                        path: self.path.clone(),
+                        ..Default::default()
+                    }),
+                    dest: None,
+                    op: goval::file_event::Op::Remove.into(),
+                })),
+                ..Default::default()
+            },
+            None,
+        );
+    }
+
+    /// The document as `channel` sees it
+    pub fn status(&self, channel: i32) -> goval::OtStatus {
+        goval::OtStatus {
+            contents: self.contents.to_string(),
+            version: self.version,
+            linked_file: Some(goval::File {
+                path: self.path.clone(),
+                ..Default::default()
+            }),
+            cursors: self
+                .cursors
+                .get(&channel)
+                .map(|cursors| {
+                    cursors
+                        .values()
+                        .map(|tracked| tracked.cursor.clone())
+                        .collect()
+                })
+                .unwrap_or_default(),
+        }
+    }
+
+    /// Who last touched each range of the current contents
+    pub fn blame(&self) -> goval::OtBlameResponse {
+        goval::OtBlameResponse {
+            ranges: self.blame.to_proto(),
+            version: self.version,
+        }
+    }
+}
+
+// A packet for an edit that wasn't made through OT
+fn system_packet(version: u32, op: Vec<goval::OtOpComponent>, crc32: u32) -> goval::OtPacket {
+    goval::OtPacket {
+        spooky_version: version,
+        version,
+        op,
+        committed: now(),
+        crc32,
+        nonce: 0,
+        user_id: *SYSTEM_USER_ID,
+        author: goval::ot_packet::Author::System.into(),
+        session: 0,
+    }
+}
+
+pub fn now() -> Option<prost_types::Timestamp> {
+    Some(prost_types::Timestamp {
+        seconds: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as i64,
+        nanos: 0,
+    })
+}
+
+fn diff(old_text: String, new_text: String) -> Vec<goval::OtOpComponent> {
+    let mut _differ = TextDiff::configure();
+    let differ = _differ.timeout(Duration::from_secs(1));
+    let diff = differ.diff_chars(&old_text, &new_text);
+
+    let mut parts: Vec<goval::OtOpComponent> = vec![];
+    let mut last_op: Option<goval::ot_op_component::OpComponent> = None;
+    for part in diff.iter_all_changes() {
+        let mut new_op: Option<goval::ot_op_component::OpComponent> = None;
+        match part.tag() {
+            similar::ChangeTag::Equal => {
+                if let Some(goval::ot_op_component::OpComponent::Skip(amount)) = last_op.clone() {
+                    last_op = Some(goval::ot_op_component::OpComponent::Skip(
+                        amount + part.value().chars().count() as u32,
+                    ))
+                } else {
+                    new_op = Some(goval::ot_op_component::OpComponent::Skip(
+                        part.value().chars().count() as u32,
+                    ));
+                }
+            }
+            similar::ChangeTag::Delete => {
+                if let Some(goval::ot_op_component::OpComponent::Delete(amount)) = last_op.clone() {
+                    last_op = Some(goval::ot_op_component::OpComponent::Delete(
+                        amount + part.value().chars().count() as u32,
+                    ))
+                } else {
+                    new_op = Some(goval::ot_op_component::OpComponent::Delete(
+                        part.value().chars().count() as u32,
+                    ));
+                }
+            }
+            similar::ChangeTag::Insert => {
+                if let Some(goval::ot_op_component::OpComponent::Insert(same)) = last_op.clone() {
+                    last_op = Some(goval::ot_op_component::OpComponent::Insert(
+                        same + part.value(),
+                    ))
+                } else {
+                    new_op = Some(goval::ot_op_component::OpComponent::Insert(
+                        part.value().to_string(),
+                    ));
+                }
+            }
+        }
+
+        if let Some(new_part) = new_op {
+            if let Some(last_part) = last_op.clone() {
+                parts.push(goval::OtOpComponent {
+                    op_component: Some(last_part),
+                });
+            }
+
+            last_op = Some(new_part);
+        }
+    }
+
+    if let Some(op) = last_op {
+        match op {
+            goval::ot_op_component::OpComponent::Skip(_) => {}
+            _ => parts.push(goval::OtOpComponent {
+                op_component: Some(op),
+            }),
+        }
+    }
+
+    parts
+}
@@ -0,0 +1,377 @@
+//! OT history in the `files` table, so documents keep their versions and
+//! `otFetchRequest` keeps working across restarts. Without a database
+//! everything here is a no-op and history only lives in memory.
+//!
+//! Writes are queued and batched, one task writes them every `INTERVAL` so
+//! documents never wait on the database while they're locked.
+
+use std::{collections::HashMap, sync::LazyLock, time::Duration};
+
+use anyhow::Result;
+use tokio::{
+    sync::{mpsc, oneshot},
+    time::Instant,
+};
+use tracing::{trace, warn};
+
+#[cfg(feature = "database")]
+use base64::{engine::general_purpose, Engine as _};
+#[cfg(feature = "database")]
+use prost::Message;
+
+static INTERVAL: LazyLock<Duration> = LazyLock::new(|| {
+    Duration::from_millis(
+        std::env::var("HOMEVAL_OT_HISTORY_INTERVAL_MS")
+            .ok()
+            .and_then(|ms| ms.parse().ok())
+            .unwrap_or(1000),
+    )
+});
+
+static QUEUE: LazyLock<mpsc::UnboundedSender<Request>> = LazyLock::new(|| {
+    let (sender, receiver) = mpsc::unbounded_channel();
+    tokio::spawn(run(receiver));
+    sender
+});
+
+enum Request {
+    Append {
+        path: String,
+        contents: ropey::Rope,
+        crc32: u32,
+        packet: goval::OtPacket,
+    },
+    Store {
+        path: String,
+        contents: ropey::Rope,
+        crc32: u32,
+        history: Vec<goval::OtPacket>,
+        blame: Option<goval::OtBlameResponse>,
+    },
+    Rename {
+        from: String,
+        to: String,
+    },
+    Flush(oneshot::Sender<()>),
+}
+
+// Everything waiting to be written for a path
+struct Batch {
+    contents: ropey::Rope,
+    crc32: u32,
+    // Whether `packets` is the whole history or just needs appending
+    replace: bool,
+    packets: Vec<goval::OtPacket>,
+    blame: Option<goval::OtBlameResponse>,
+}
+
+/// What was last persisted for a path
+pub struct Stored {
+    pub crc32: u32,
+    pub contents: String,
+    pub history: Vec<goval::OtPacket>,
+    /// Who wrote what in the first packet, when it's a checkpoint
+    pub blame: Option<goval::OtBlameResponse>,
+}
+
+/// Reads what's stored for `path`, after anything still queued is written
+pub async fn load(path: &str) -> Result<Option<Stored>> {
+    flush().await;
+
+    #[cfg(feature = "database")]
+    if let Some(database) = crate::DATABASE.get() {
+        use sea_orm::EntityTrait;
+
+        let row = match entity::files::Entity::find_by_id(path.to_string())
+            .one(database)
+            .await?
+        {
+            Some(row) => row,
+            None => return Ok(None),
+        };
+
+        let mut history = Vec::with_capacity(row.history.len());
+        for packet in row.history {
+            history.push(decode(&packet)?);
+        }
+
+        let blame = match row.blame {
+            Some(blame) => Some(goval::OtBlameResponse::decode(blame.as_slice())?),
+            None => None,
+        };
+
+        return Ok(Some(Stored {
+            crc32: row.crc32 as u32,
+            contents: row.contents,
+            history,
+            blame,
+        }));
+    }
+
+    let _ = path;
+    Ok(None)
+}
+
+/// Replaces everything stored for `path`. `blame` goes with the first packet
+/// of `history` if it's a checkpoint.
+pub fn store(
+    path: &str,
+    contents: ropey::Rope,
+    crc32: u32,
+    history: Vec<goval::OtPacket>,
+    blame: Option<goval::OtBlameResponse>,
+) {
+    queue(Request::Store {
+        path: path.to_string(),
+        contents,
+        crc32,
+        history,
+        blame,
+    });
+}
+
+/// Adds a committed packet to the end of the stored history
+pub fn append(path: &str, contents: ropey::Rope, crc32: u32, packet: goval::OtPacket) {
+    queue(Request::Append {
+        path: path.to_string(),
+        contents,
+        crc32,
+        packet,
+    });
+}
+
+/// Moves what's stored for `from` to `to`, replacing anything already there
+pub fn rename(from: &str, to: &str) {
+    queue(Request::Rename {
+        from: from.to_string(),
+        to: to.to_string(),
+    });
+}
+
+/// Waits for everything queued so far to be written
+pub async fn flush() {
+    let (reply, flushed) = oneshot::channel();
+    queue(Request::Flush(reply));
+    let _ = flushed.await;
+}
+
+fn queue(request: Request) {
+    #[cfg(feature = "database")]
+    if crate::DATABASE.get().is_some() {
+        let _ = QUEUE.send(request);
+        return;
+    }
+
+    // Dropping a flush's reply lets it return right away
+    let _ = request;
+}
+
+async fn run(mut receiver: mpsc::UnboundedReceiver<Request>) {
+    let mut pending: HashMap<String, Batch> = HashMap::new();
+    let mut deadline = None;
+
+    loop {
+        let request = match deadline {
+            Some(deadline) => tokio::time::timeout_at(deadline, receiver.recv()).await,
+            None => Ok(receiver.recv().await),
+        };
+        let request = match request {
+            Ok(Some(request)) => request,
+            Ok(None) => {
+                write(&mut pending).await;
+                break;
+            }
+            Err(_) => {
+                write(&mut pending).await;
+                deadline = None;
+                continue;
+            }
+        };
+
+        match request {
+            Request::Append {
+                path,
+                contents,
+                crc32,
+                packet,
+            } => {
+                let batch = pending.entry(path).or_insert_with(|| Batch {
+                    contents: ropey::Rope::new(),
+                    crc32,
+                    replace: false,
+                    packets: vec![],
+                    blame: None,
+                });
+                batch.contents = contents;
+                batch.crc32 = crc32;
+                batch.packets.push(packet);
+            }
+            Request::Store {
+                path,
+                contents,
+                crc32,
+                history,
+                blame,
+            } => {
+                pending.insert(
+                    path,
+                    Batch {
+                        contents,
+                        crc32,
+                        replace: true,
+                        packets: history,
+                        blame,
+                    },
+                );
+            }
+            Request::Rename { from, to } => {
+                // Anything queued under either name has to land first
+                write(&mut pending).await;
+                if let Err(err) = rename_row(&from, &to).await {
+                    warn!(%err, from, to, "Error moving stored OT history");
+                }
+            }
+            Request::Flush(reply) => {
+                write(&mut pending).await;
+                let _ = reply.send(());
+            }
+        }
+
+        // Written `INTERVAL` after the first of the batch, however busy it gets
+        if pending.is_empty() {
+            deadline = None;
+        } else if deadline.is_none() {
+            deadline = Some(Instant::now() + *INTERVAL);
+        }
+    }
+}
+
+async fn write(pending: &mut HashMap<String, Batch>) {
+    for (path, batch) in pending.drain() {
+        let contents = batch.contents.to_string();
+        let result = if batch.replace {
+            replace_row(
+                &path,
+                &contents,
+                batch.crc32,
+                &batch.packets,
+                batch.blame.as_ref(),
+            )
+            .await
+        } else {
+            append_row(&path, &contents, batch.crc32, &batch.packets).await
+        };
+
+        match result {
+            Ok(()) => trace!(
+                path,
+                packets = batch.packets.len(),
+                replace = batch.replace,
+                "Stored OT history"
+            ),
+            Err(err) => warn!(%err, path, "Error storing OT history"),
+        }
+    }
+}
+
+async fn replace_row(
+    path: &str,
+    contents: &str,
+    crc32: u32,
+    history: &[goval::OtPacket],
+    blame: Option<&goval::OtBlameResponse>,
+) -> Result<()> {
+    #[cfg(feature = "database")]
+    if let Some(database) = crate::DATABASE.get() {
+        use sea_orm::{sea_query::OnConflict, ActiveValue, EntityTrait};
+
+        let row = entity::files::ActiveModel {
+            name: ActiveValue::Set(path.to_string()),
+            crc32: ActiveValue::Set(crc32 as i32),
+            contents: ActiveValue::Set(contents.to_string()),
+            history: ActiveValue::Set(history.iter().map(encode).collect()),
+            blame: ActiveValue::Set(blame.map(|blame| blame.encode_to_vec())),
+        };
+
+        entity::files::Entity::insert(row)
+            .on_conflict(
+                OnConflict::column(entity::files::Column::Name)
+                    .update_columns([
+                        entity::files::Column::Crc32,
+                        entity::files::Column::Contents,
+                        entity::files::Column::History,
+                        entity::files::Column::Blame,
+                    ])
+                    .to_owned(),
+            )
+            .exec(database)
+            .await?;
+        return Ok(());
+    }
+
+    let _ = (path, contents, crc32, history, blame);
+    Ok(())
+}
+
+async fn append_row(
+    path: &str,
+    contents: &str,
+    crc32: u32,
+    packets: &[goval::OtPacket],
+) -> Result<()> {
+    #[cfg(feature = "database")]
+    if let Some(database) = crate::DATABASE.get() {
+        use sea_orm::{sea_query::Expr, ColumnTrait, EntityTrait, QueryFilter};
+
+        // Appending in place saves sending the whole history back every edit
+        entity::files::Entity::update_many()
+            .col_expr(
+                entity::files::Column::History,
+                Expr::cust_with_values(
+                    "array_cat(\"history\", $1)",
+                    [packets.iter().map(encode).collect::<Vec<_>>()],
+                ),
+            )
+            .col_expr(entity::files::Column::Crc32, Expr::value(crc32 as i32))
+            .col_expr(entity::files::Column::Contents, Expr::value(contents))
+            .filter(entity::files::Column::Name.eq(path))
+            .exec(database)
+            .await?;
+        return Ok(());
+    }
+
+    let _ = (path, contents, crc32, packets);
+    Ok(())
+}
+
+async fn rename_row(from: &str, to: &str) -> Result<()> {
+    #[cfg(feature = "database")]
+    if let Some(database) = crate::DATABASE.get() {
+        use sea_orm::{sea_query::Expr, ColumnTrait, EntityTrait, QueryFilter};
+
+        entity::files::Entity::delete_by_id(to.to_string())
+            .exec(database)
+            .await?;
+        entity::files::Entity::update_many()
+            .col_expr(entity::files::Column::Name, Expr::value(to))
+            .filter(entity::files::Column::Name.eq(from))
+            .exec(database)
+            .await?;
+        return Ok(());
+    }
+
+    let _ = (from, to);
+    Ok(())
+}
+
+#[cfg(feature = "database")]
+fn encode(packet: &goval::OtPacket) -> String {
+    general_purpose::STANDARD.encode(packet.encode_to_vec())
+}
+
+#[cfg(feature = "database")]
+fn decode(packet: &str) -> Result<goval::OtPacket> {
+    Ok(goval::OtPacket::decode(
+        general_purpose::STANDARD.decode(packet)?.as_slice(),
+    )?)
+}
@@ -0,0 +1,356 @@
+//! Operational transformation for OT packets. Ops are a list of skips, deletes
+//! and inserts measured in chars, anything after the last component is left
+//! untouched.
+
+use goval::ot_op_component::OpComponent;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Component {
+    Skip(usize),
+    Delete(usize),
+    Insert(String),
+}
+
+pub type Op = Vec<Component>;
+
+pub fn from_proto(op: &[goval::OtOpComponent]) -> Op {
+    let mut builder = Builder::default();
+    for component in op {
+        match &component.op_component {
+            Some(OpComponent::Skip(count)) => builder.skip(*count as usize),
+            Some(OpComponent::Delete(count)) => builder.delete(*count as usize),
+            Some(OpComponent::Insert(text)) => builder.insert(text),
+            None => {}
+        }
+    }
+    builder.finish()
+}
+
+pub fn to_proto(op: &Op) -> Vec<goval::OtOpComponent> {
+    op.iter()
+        .map(|component| goval::OtOpComponent {
+            op_component: Some(match component {
+                Component::Skip(count) => OpComponent::Skip(*count as u32),
+                Component::Delete(count) => OpComponent::Delete(*count as u32),
+                Component::Insert(text) => OpComponent::Insert(text.clone()),
+            }),
+        })
+        .collect()
+}
+
+// Merges neighbouring components of the same kind and drops empty ones and the
+// trailing skip, so equivalent ops compare equal
+#[derive(Default)]
+struct Builder {
+    op: Op,
+}
+
+impl Builder {
+    fn skip(&mut self, count: usize) {
+        if count == 0 {
+            return;
+        }
+        match self.op.last_mut() {
+            Some(Component::Skip(last)) => *last += count,
+            _ => self.op.push(Component::Skip(count)),
+        }
+    }
+
+    fn delete(&mut self, count: usize) {
+        if count == 0 {
+            return;
+        }
+        match self.op.last_mut() {
+            Some(Component::Delete(last)) => *last += count,
+            _ => self.op.push(Component::Delete(count)),
+        }
+    }
+
+    fn insert(&mut self, text: &str) {
+        if text.is_empty() {
+            return;
+        }
+        match self.op.last_mut() {
+            Some(Component::Insert(last)) => last.push_str(text),
+            _ => self.op.push(Component::Insert(text.to_string())),
+        }
+    }
+
+    fn finish(mut self) -> Op {
+        if let Some(Component::Skip(_)) = self.op.last() {
+            self.op.pop();
+        }
+        self.op
+    }
+}
+
+// Walks an op a piece at a time, splitting skips and deletes as needed
+struct Cursor<'a> {
+    op: &'a [Component],
+    index: usize,
+    // How much of the current skip or delete is already used up
+    offset: usize,
+}
+
+impl<'a> Cursor<'a> {
+    fn new(op: &'a [Component]) -> Self {
+        Self {
+            op,
+            index: 0,
+            offset: 0,
+        }
+    }
+
+    fn peek(&self) -> Option<Component> {
+        self.op.get(self.index).map(|component| match component {
+            Component::Skip(count) => Component::Skip(count - self.offset),
+            Component::Delete(count) => Component::Delete(count - self.offset),
+            Component::Insert(text) => Component::Insert(text.clone()),
+        })
+    }
+
+    /// Consumes `count` chars of the current skip or delete, or the whole insert
+    fn advance(&mut self, count: usize) {
+        match self.op.get(self.index) {
+            Some(Component::Skip(total)) | Some(Component::Delete(total))
+                if self.offset + count < *total =>
+            {
+                self.offset += count
+            }
+            Some(_) => {
+                self.index += 1;
+                self.offset = 0;
+            }
+            None => {}
+        }
+    }
+}
+
+/// Rewrites `op` so it can be applied after `applied`, when both were made
+/// against the same document. When both insert at the same spot the text
+/// from `applied` ends up first.
+pub fn transform(op: &Op, applied: &Op) -> Op {
+    transform_side(op, applied, false)
+}
+
+// `ours_first` decides whose text goes first when both insert at the same spot
+fn transform_side(op: &Op, applied: &Op, ours_first: bool) -> Op {
+    let mut builder = Builder::default();
+    let mut ours = Cursor::new(op);
+    let mut theirs = Cursor::new(applied);
+
+    loop {
+        match (ours.peek(), theirs.peek()) {
+            (None, _) => break,
+            (Some(Component::Insert(text)), _) if ours_first => {
+                builder.insert(&text);
+                ours.advance(0);
+            }
+            // Text inserted by the applied op has to be skipped over
+            (_, Some(Component::Insert(text))) => {
+                builder.skip(text.chars().count());
+                theirs.advance(0);
+            }
+            (Some(Component::Insert(text)), _) => {
+                builder.insert(&text);
+                ours.advance(0);
+            }
+            // Past the end of the applied op nothing changed
+            (Some(Component::Skip(count)), None) => {
+                builder.skip(count);
+                ours.advance(count);
+            }
+            (Some(Component::Delete(count)), None) => {
+                builder.delete(count);
+                ours.advance(count);
+            }
+            (Some(Component::Skip(mine)), Some(Component::Skip(other))) => {
+                let count = mine.min(other);
+                builder.skip(count);
+                ours.advance(count);
+                theirs.advance(count);
+            }
+            // Already deleted, nothing left to skip over
+            (Some(Component::Skip(mine)), Some(Component::Delete(other))) => {
+                let count = mine.min(other);
+                ours.advance(count);
+                theirs.advance(count);
+            }
+            (Some(Component::Delete(mine)), Some(Component::Skip(other))) => {
+                let count = mine.min(other);
+                builder.delete(count);
+                ours.advance(count);
+                theirs.advance(count);
+            }
+            // Both deleted the same text, only one of them needs to
+            (Some(Component::Delete(mine)), Some(Component::Delete(other))) => {
+                let count = mine.min(other);
+                ours.advance(count);
+                theirs.advance(count);
+            }
+        }
+    }
+
+    builder.finish()
+}
+
+/// Where a position in the document ends up once `op` is applied. Text
+/// inserted right at the position pushes it forward, deleting the text around
+/// it moves it to the start of the deletion.
+pub fn transform_index(index: usize, op: &Op) -> usize {
+    let mut moved = index;
+    // Position in the document before the op
+    let mut position = 0;
+
+    for component in op {
+        if position > index {
+            break;
+        }
+
+        match component {
+            Component::Skip(count) => position += count,
+            Component::Insert(text) => moved += text.chars().count(),
+            Component::Delete(count) => {
+                if position < index {
+                    moved -= (*count).min(index - position);
+                }
+                position += count;
+            }
+        }
+    }
+
+    moved
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn apply(document: &str, op: &Op) -> String {
+        let mut chars = document.chars();
+        let mut result = String::new();
+        for component in op {
+            match component {
+                Component::Skip(count) => result.extend(chars.by_ref().take(*count)),
+                Component::Delete(count) => {
+                    chars.by_ref().take(*count).for_each(drop);
+                }
+                Component::Insert(text) => result.push_str(text),
+            }
+        }
+        result.extend(chars);
+        result
+    }
+
+    // `a` and `b` were both made against `document`, `a` got there first
+    fn converge(document: &str, a: &Op, b: &Op) -> String {
+        let left = apply(&apply(document, a), &transform(b, a));
+        let right = apply(&apply(document, b), &transform_side(a, b, true));
+        assert_eq!(left, right);
+        left
+    }
+
+    fn op(components: &[Component]) -> Op {
+        let mut builder = Builder::default();
+        for component in components {
+            match component {
+                Component::Skip(count) => builder.skip(*count),
+                Component::Delete(count) => builder.delete(*count),
+                Component::Insert(text) => builder.insert(text),
+            }
+        }
+        builder.finish()
+    }
+
+    fn insert(index: usize, text: &str) -> Op {
+        op(&[Component::Skip(index), Component::Insert(text.to_string())])
+    }
+
+    fn delete(index: usize, count: usize) -> Op {
+        op(&[Component::Skip(index), Component::Delete(count)])
+    }
+
+    #[test]
+    fn concurrent_inserts_at_the_same_index() {
+        let a = insert(2, "aa");
+        let b = insert(2, "bb");
+
+        assert_eq!(transform(&b, &a), insert(4, "bb"));
+        assert_eq!(converge("hello", &a, &b), "heaabbllo");
+    }
+
+    #[test]
+    fn insert_inside_a_deleted_range() {
+        let a = delete(1, 4);
+        let b = insert(3, "X");
+
+        assert_eq!(transform(&b, &a), insert(1, "X"));
+        assert_eq!(
+            transform(&a, &b),
+            op(&[
+                Component::Skip(1),
+                Component::Delete(2),
+                Component::Skip(1),
+                Component::Delete(2),
+            ])
+        );
+        assert_eq!(converge("abcdefg", &a, &b), "aXfg");
+    }
+
+    #[test]
+    fn overlapping_deletes() {
+        let a = delete(1, 4);
+        let b = delete(3, 4);
+
+        assert_eq!(transform(&b, &a), delete(1, 2));
+        assert_eq!(converge("abcdefgh", &a, &b), "ah");
+    }
+
+    #[test]
+    fn same_delete_twice() {
+        let a = delete(2, 3);
+
+        assert_eq!(transform(&a, &a), vec![]);
+        assert_eq!(converge("abcdefg", &a, &a), "abfg");
+    }
+
+    #[test]
+    fn converges() {
+        let document = "the quick brown fox";
+        let ops = [
+            insert(0, "so "),
+            insert(4, "very "),
+            insert(19, "!"),
+            delete(0, 4),
+            delete(4, 6),
+            delete(8, 11),
+            op(&[
+                Component::Skip(4),
+                Component::Delete(5),
+                Component::Insert("slow".to_string()),
+            ]),
+            op(&[
+                Component::Skip(10),
+                Component::Insert("red".to_string()),
+                Component::Delete(5),
+                Component::Skip(1),
+                Component::Insert("c".to_string()),
+                Component::Delete(1),
+            ]),
+        ];
+
+        for a in &ops {
+            for b in &ops {
+                converge(document, a, b);
+            }
+        }
+    }
+
+    #[test]
+    fn indexes_follow_edits() {
+        assert_eq!(transform_index(3, &insert(3, "ab")), 5);
+        assert_eq!(transform_index(3, &insert(4, "ab")), 3);
+        assert_eq!(transform_index(3, &delete(1, 4)), 1);
+        assert_eq!(transform_index(6, &delete(1, 4)), 2);
+    }
+}
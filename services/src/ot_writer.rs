@@ -0,0 +1,234 @@
+//! Disk writes for OT documents. Edits are coalesced and written once they
+//! stop coming for a moment, or at the latest after `MAX_LATENCY`, instead of
+//! rewriting the whole file for every packet.
+
+use std::{
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, AtomicU32, Ordering},
+        Arc, LazyLock,
+    },
+    time::Duration,
+};
+
+use anyhow::{format_err, Result};
+use tokio::{
+    sync::{mpsc, oneshot},
+    time::Instant,
+};
+use tracing::{debug, trace, warn};
+
+use crate::atomic;
+
+static DEBOUNCE: LazyLock<Duration> = LazyLock::new(|| {
+    Duration::from_millis(
+        std::env::var("HOMEVAL_OT_WRITE_DEBOUNCE_MS")
+            .ok()
+            .and_then(|ms| ms.parse().ok())
+            .unwrap_or(250),
+    )
+});
+
+static MAX_LATENCY: LazyLock<Duration> = LazyLock::new(|| {
+    Duration::from_millis(
+        std::env::var("HOMEVAL_OT_WRITE_MAX_LATENCY_MS")
+            .ok()
+            .and_then(|ms| ms.parse().ok())
+            .unwrap_or(2000),
+    )
+});
+
+enum Request {
+    Write {
+        path: String,
+        contents: ropey::Rope,
+        create: bool,
+    },
+    Flush(oneshot::Sender<Result<()>>),
+    Cancel,
+}
+
+struct Pending {
+    path: String,
+    contents: ropey::Rope,
+    create: bool,
+    // When it has to be written even if edits keep coming
+    deadline: Instant,
+}
+
+/// Writes a document to disk in the background, stops once dropped after
+/// writing anything still pending.
+pub struct Writer {
+    sender: mpsc::UnboundedSender<Request>,
+    written: Arc<AtomicU32>,
+    gone: Arc<AtomicBool>,
+}
+
+impl Writer {
+    /// `crc32` is what's on disk already
+    pub fn new(crc32: u32) -> Writer {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let written = Arc::new(AtomicU32::new(crc32));
+        let gone = Arc::new(AtomicBool::new(false));
+
+        tokio::spawn(run(receiver, written.clone(), gone.clone()));
+
+        Writer {
+            sender,
+            written,
+            gone,
+        }
+    }
+
+    /// Queues `contents` to be written to `path`, replacing anything queued
+    /// before. It's dropped if the file is gone by the time it's written.
+    pub fn write(&self, path: &str, contents: ropey::Rope) {
+        let _ = self.sender.send(Request::Write {
+            path: path.to_string(),
+            contents,
+            create: false,
+        });
+    }
+
+    /// Like [`Writer::write`] but creates the file if it doesn't exist
+    pub fn recreate(&self, path: &str, contents: ropey::Rope) {
+        let _ = self.sender.send(Request::Write {
+            path: path.to_string(),
+            contents,
+            create: true,
+        });
+    }
+
+    /// Writes whatever is queued right away, and waits for it to hit the disk.
+    /// Fails if it was dropped because the file is gone.
+    pub async fn flush(&self) -> Result<()> {
+        let (reply, result) = oneshot::channel();
+        self.sender
+            .send(Request::Flush(reply))
+            .map_err(|_| format_err!("OT writer has stopped"))?;
+        result.await?
+    }
+
+    /// Drops whatever is queued without writing it
+    pub fn cancel(&self) {
+        let _ = self.sender.send(Request::Cancel);
+    }
+
+    /// crc32 of the contents last written, so our own writes can be told
+    /// apart from changes made by something else
+    pub fn written(&self) -> u32 {
+        self.written.load(Ordering::SeqCst)
+    }
+
+    pub fn set_written(&self, crc32: u32) {
+        self.written.store(crc32, Ordering::SeqCst)
+    }
+
+    /// Whether the last write was dropped because the file was missing. The
+    /// watcher doesn't report a file that's removed right after it was
+    /// written, this catches that.
+    pub fn gone(&self) -> bool {
+        self.gone.load(Ordering::SeqCst)
+    }
+}
+
+async fn run(
+    mut receiver: mpsc::UnboundedReceiver<Request>,
+    written: Arc<AtomicU32>,
+    gone: Arc<AtomicBool>,
+) {
+    let mut pending: Option<Pending> = None;
+
+    loop {
+        let request = match &pending {
+            Some(queued) => {
+                let wake = (Instant::now() + *DEBOUNCE).min(queued.deadline);
+                match tokio::time::timeout_at(wake, receiver.recv()).await {
+                    Ok(request) => request,
+                    Err(_) => {
+                        if let Some(queued) = pending.take() {
+                            if let Err(err) = write(&queued, &written, &gone).await {
+                                warn!(%err, path = queued.path, "Error writing OT document");
+                            }
+                        }
+                        continue;
+                    }
+                }
+            }
+            None => receiver.recv().await,
+        };
+
+        match request {
+            Some(Request::Write {
+                path,
+                contents,
+                create,
+            }) => {
+                let (deadline, create) = match &pending {
+                    Some(queued) => (queued.deadline, create || queued.create),
+                    None => (Instant::now() + *MAX_LATENCY, create),
+                };
+                pending = Some(Pending {
+                    path,
+                    contents,
+                    create,
+                    deadline,
+                });
+            }
+            Some(Request::Flush(reply)) => {
+                let result = match pending.take() {
+                    Some(queued) => match write(&queued, &written, &gone).await {
+                        Ok(false) => Err(format_err!("{}: no such file or directory", queued.path)),
+                        Ok(true) => Ok(()),
+                        Err(err) => Err(err),
+                    },
+                    None => Ok(()),
+                };
+                let _ = reply.send(result);
+            }
+            Some(Request::Cancel) => pending = None,
+            None => {
+                if let Some(queued) = pending.take() {
+                    if let Err(err) = write(&queued, &written, &gone).await {
+                        warn!(%err, path = queued.path, "Error writing OT document");
+                    }
+                }
+                break;
+            }
+        }
+    }
+}
+
+// Returns false if the file was gone so nothing was written
+async fn write(queued: &Pending, written: &AtomicU32, gone: &AtomicBool) -> Result<bool> {
+    let path = Path::new(&queued.path);
+
+    if queued.create {
+        // Recreates the file's directory if it was removed along with it
+        if let Some(parent) = path
+            .parent()
+            .filter(|parent| !parent.as_os_str().is_empty())
+        {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+    } else if tokio::fs::metadata(path).await.is_err() {
+        // Moved or removed since, the document writes it again once it has
+        // caught up with that
+        debug!(
+            path = queued.path,
+            "OT document's file is gone, not writing it"
+        );
+        gone.store(true, Ordering::SeqCst);
+        return Ok(false);
+    }
+    gone.store(false, Ordering::SeqCst);
+
+    let contents = queued.contents.to_string();
+    let crc32 = crc32fast::hash(contents.as_bytes());
+    // Before the write, so the event it causes is already recognised
+    written.store(crc32, Ordering::SeqCst);
+
+    atomic::write_atomic(path, contents.as_bytes()).await?;
+    trace!(path = queued.path, crc32, "Wrote OT document");
+    Ok(true)
+}
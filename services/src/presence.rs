@@ -2,7 +2,7 @@ pub struct Presence {
     users: Vec<goval::User>,
     files: HashMap<i32, goval::FileOpened>,
 }
-use crate::{ClientInfo, IPCMessage, SendSessions};
+use crate::{paths, ClientInfo, IPCMessage, SendSessions};
 use std::{
     collections::HashMap,
     time::{SystemTime, UNIX_EPOCH},
@@ -79,7 +79,7 @@ impl traits::Service for Presence {
 
                 let _inner = goval::FileOpened {
                     user_id: user.id,
-                    file: file.file,
+                    file: paths::resolve(&file.file)?.relative,
                     session,
                     timestamp,
                 };
@@ -0,0 +1,271 @@
+use std::{sync::LazyLock, time::Instant};
+
+pub static START_TIME: LazyLock<Instant> = LazyLock::new(Instant::now);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AttachRule {
+    /// `openChan` requests with the same non-empty name share a channel
+    Named,
+    /// Every `openChan` for the service shares a single channel
+    Singleton,
+}
+
+#[derive(Debug)]
+pub struct ServiceInfo {
+    pub name: &'static str,
+    pub version: u32,
+    /// Command bodies handled by the service and the ones it sends without
+    /// being asked, as named in goval.proto
+    pub bodies: &'static [&'static str],
+    pub attach: AttachRule,
+}
+
+impl ServiceInfo {
+    /// Stub services accept channels but never do anything with them
+    pub fn is_stub(&self) -> bool {
+        self.bodies.is_empty()
+    }
+
+    pub fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "name": self.name,
+            "version": self.version,
+            "bodies": self.bodies,
+            "singleton": self.attach == AttachRule::Singleton,
+        })
+    }
+}
+
+pub static SERVICES: &[ServiceInfo] = &[
+    ServiceInfo {
+        name: "chat",
+        version: 1,
+        bodies: &["chatMessage", "chatTyping", "chatScrollback"],
+        attach: AttachRule::Named,
+    },
+    ServiceInfo {
+        name: "gcsfiles",
+        version: 1,
+        bodies: &[
+            "readdir",
+            "mkdir",
+            "read",
+            "remove",
+            "move",
+            "write",
+            "stat",
+            "checkChanges",
+            "readMetaRequest",
+            "writeMetaRequest",
+            "appendMetaRequest",
+            "tryRemove",
+            "persist",
+            "persistMirror",
+            "fsTakeLock",
+            "fsReleaseLock",
+            "transferStart",
+            "transferChunk",
+            "transferComplete",
+            "transferCancel",
+            "transfer",
+        ],
+        attach: AttachRule::Named,
+    },
+    ServiceInfo {
+        name: "filewatcher",
+        version: 1,
+        bodies: &["subscribeFile", "fileEvent"],
+        attach: AttachRule::Named,
+    },
+    ServiceInfo {
+        name: "presence",
+        version: 1,
+        bodies: &[
+            "followUser",
+            "unfollowUser",
+            "openFile",
+            "fileOpened",
+            "roster",
+            "join",
+            "part",
+        ],
+        attach: AttachRule::Named,
+    },
+    ServiceInfo {
+        name: "ot",
+        version: 1,
+        bodies: &[
+            "otLinkFile",
+            "ot",
+            "otNewCursor",
+            "otDeleteCursor",
+            "otFetchRequest",
+            "otTransformSelectionRequest",
+            "otBlameRequest",
+            "flush",
+        ],
+        attach: AttachRule::Named,
+    },
+    ServiceInfo {
+        name: "snapshot",
+        version: 1,
+        bodies: &["fsSnapshot", "fsSnapshotEvent", "snapshotEvent"],
+        attach: AttachRule::Named,
+    },
+    ServiceInfo {
+        name: "null",
+        version: 1,
+        bodies: &[],
+        attach: AttachRule::Named,
+    },
+    // git is just used for replspace api stuff from what I can tell, so its
+    // just easier to have it as one instance.
+    ServiceInfo {
+        name: "git",
+        version: 1,
+        bodies: &[
+            "replspaceApiGitHubToken",
+            "replspaceApiCloseFile",
+            "replspaceApiGetGitHubToken",
+            "replspaceApiOpenFile",
+        ],
+        attach: AttachRule::Singleton,
+    },
+    ServiceInfo {
+        name: "open",
+        version: 1,
+        bodies: &[],
+        attach: AttachRule::Named,
+    },
+    ServiceInfo {
+        name: "output",
+        version: 1,
+        bodies: &[
+            "runMain",
+            "clear",
+            "input",
+            "resizeTerm",
+            "state",
+            "outputBlockStartEvent",
+            "outputBlockEndEvent",
+        ],
+        attach: AttachRule::Named,
+    },
+    ServiceInfo {
+        name: "shell",
+        version: 1,
+        bodies: &["input", "resizeTerm"],
+        attach: AttachRule::Named,
+    },
+    ServiceInfo {
+        name: "toolchain",
+        version: 1,
+        bodies: &["nixModulesGetRequest", "toolchainGetRequest"],
+        attach: AttachRule::Named,
+    },
+    ServiceInfo {
+        name: "dotreplit",
+        version: 1,
+        bodies: &["dotReplitGetRequest"],
+        attach: AttachRule::Named,
+    },
+    ServiceInfo {
+        name: "exec",
+        version: 1,
+        bodies: &["exec", "state"],
+        attach: AttachRule::Named,
+    },
+];
+
+pub fn service(name: &str) -> Option<&'static ServiceInfo> {
+    SERVICES.iter().find(|service| service.name == name)
+}
+
+pub fn uptime() -> u64 {
+    START_TIME.elapsed().as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeSet;
+
+    fn handler(service: &str) -> &'static str {
+        match service {
+            "chat" => include_str!("chat.rs"),
+            "gcsfiles" => include_str!("gcsfiles.rs"),
+            "filewatcher" => include_str!("filewatcher.rs"),
+            "presence" => include_str!("presence.rs"),
+            "ot" => include_str!("ot.rs"),
+            "snapshot" => include_str!("snapshot.rs"),
+            "git" => include_str!("git.rs"),
+            "output" => include_str!("output.rs"),
+            "shell" => include_str!("shell.rs"),
+            "toolchain" => include_str!("toolchain.rs"),
+            "dotreplit" => include_str!("dotreplit.rs"),
+            "exec" => include_str!("exec.rs"),
+            "null" | "open" => include_str!("stub.rs"),
+            _ => panic!("No handler source for {}", service),
+        }
+    }
+
+    // Every `Body::` in `source` as named in goval.proto, and whether it's
+    // matched on rather than sent
+    fn bodies(source: &str) -> Vec<(String, bool)> {
+        source
+            .split("Body::")
+            .skip(1)
+            .map(|rest| {
+                let name: String = rest
+                    .chars()
+                    .take_while(|c| c.is_ascii_alphanumeric())
+                    .collect();
+                let after = &rest[name.len()..];
+                let after = match after.strip_prefix('(') {
+                    Some(args) => args.split_once(')').map_or("", |(_, tail)| tail),
+                    None => after,
+                };
+                let after = after.trim_start();
+                let matched =
+                    after.starts_with("=>") || after.starts_with('|') || after.starts_with("= ");
+
+                let mut chars = name.chars();
+                let name = match chars.next() {
+                    Some(first) => first.to_ascii_lowercase().to_string() + chars.as_str(),
+                    None => name,
+                };
+                (name, matched)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn bodies_match_handlers() {
+        for service in SERVICES {
+            let listed: BTreeSet<_> = service.bodies.iter().map(|body| body.to_string()).collect();
+            let found = bodies(handler(service.name));
+
+            let handled: BTreeSet<_> = found
+                .iter()
+                .filter(|(_, matched)| *matched)
+                .map(|(name, _)| name.clone())
+                .collect();
+            let unlisted: Vec<_> = handled.difference(&listed).collect();
+            assert!(
+                unlisted.is_empty(),
+                "{} handles {:?} without listing them",
+                service.name,
+                unlisted
+            );
+
+            let used: BTreeSet<_> = found.into_iter().map(|(name, _)| name).collect();
+            let unused: Vec<_> = listed.difference(&used).collect();
+            assert!(
+                unused.is_empty(),
+                "{} lists {:?} but never handles or sends them",
+                service.name,
+                unused
+            );
+        }
+    }
+}
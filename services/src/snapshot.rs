@@ -1,8 +1,61 @@
-pub struct Snapshot {}
+pub struct Snapshot {
+    // Forwards snapshots taken or restored anywhere to this channel's sessions
+    events: tokio::task::JoinHandle<()>,
+}
 
 use super::traits;
+use crate::{
+    snapshot_store::{self, SnapshotEvent},
+    ChannelMessage, SendSessions,
+};
 use anyhow::{format_err, Result};
 use async_trait::async_trait;
+use tokio::sync::broadcast::error::RecvError;
+use tracing::warn;
+
+impl Snapshot {
+    pub fn new(sender: tokio::sync::mpsc::UnboundedSender<ChannelMessage>) -> Self {
+        let mut events = snapshot_store::subscribe();
+
+        let events = tokio::spawn(async move {
+            loop {
+                let body = match events.recv().await {
+                    Ok(SnapshotEvent::Taken(id)) => {
+                        goval::command::Body::FsSnapshotEvent(goval::FsSnapshotEvent {
+                            sources: vec![id],
+                        })
+                    }
+                    Ok(SnapshotEvent::Restored(id)) => {
+                        goval::command::Body::SnapshotEvent(goval::SnapshotEvent {
+                            sources: vec![id],
+                        })
+                    }
+                    Err(RecvError::Lagged(missed)) => {
+                        warn!(missed, "Snapshot events lagged");
+                        continue;
+                    }
+                    Err(RecvError::Closed) => break,
+                };
+
+                let notif = goval::Command {
+                    body: Some(body),
+                    ..Default::default()
+                };
+                if sender
+                    .send(ChannelMessage::ExternalMessage(
+                        notif,
+                        SendSessions::Everyone,
+                    ))
+                    .is_err()
+                {
+                    break;
+                }
+            }
+        });
+
+        Self { events }
+    }
+}
 
 #[async_trait]
 impl traits::Service for Snapshot {
@@ -19,6 +72,7 @@ impl traits::Service for Snapshot {
 
         match body {
             goval::command::Body::FsSnapshot(_) => {
+                snapshot_store::take().await?;
                 let ok = goval::Command {
                     body: Some(goval::command::Body::Ok(goval::Ok {})),
                     ..Default::default()
@@ -28,4 +82,9 @@ impl traits::Service for Snapshot {
             _ => Ok(None),
         }
     }
+
+    async fn shutdown(self: Box<Self>, _info: &super::types::ChannelInfo) -> Result<()> {
+        self.events.abort();
+        Ok(())
+    }
 }
@@ -0,0 +1,480 @@
+use crate::{changes, paths, PathEscape};
+use anyhow::{format_err, Result};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::{BTreeMap, HashSet},
+    fs,
+    io::{self, Read},
+    path::{Path, PathBuf},
+    sync::LazyLock,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tokio::sync::{broadcast, Mutex};
+use tracing::{debug, info, warn};
+
+// Snapshots live in the workspace so they move with it, file contents are
+// stored once per sha256 under `objects/` and each snapshot is a manifest
+// mapping paths to objects.
+static STORE_DIR: &str = ".homeval/snapshots";
+
+// Number of snapshots to keep, older ones are deleted when a new one is taken
+static RETENTION: LazyLock<usize> = LazyLock::new(|| {
+    std::env::var("HOMEVAL_SNAPSHOT_RETENTION")
+        .ok()
+        .and_then(|count| count.parse().ok())
+        .filter(|count| *count > 0)
+        .unwrap_or(20)
+});
+
+// Taking, restoring and pruning snapshots all go one at a time
+static LOCK: Mutex<()> = Mutex::const_new(());
+
+static EVENTS: LazyLock<broadcast::Sender<SnapshotEvent>> =
+    LazyLock::new(|| broadcast::channel(16).0);
+
+#[derive(Clone, Debug)]
+pub enum SnapshotEvent {
+    Taken(String),
+    Restored(String),
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct FileEntry {
+    hash: String,
+    size: u64,
+    mode: u32,
+    // Lets the next snapshot skip rehashing files that haven't been touched
+    modified: u128,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct Manifest {
+    id: String,
+    created: u64,
+    files: BTreeMap<String, FileEntry>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct SnapshotInfo {
+    pub id: String,
+    pub created: u64,
+    pub files: usize,
+    pub size: u64,
+}
+
+impl From<&Manifest> for SnapshotInfo {
+    fn from(manifest: &Manifest) -> Self {
+        SnapshotInfo {
+            id: manifest.id.clone(),
+            created: manifest.created,
+            files: manifest.files.len(),
+            size: manifest.files.values().map(|file| file.size).sum(),
+        }
+    }
+}
+
+pub fn subscribe() -> broadcast::Receiver<SnapshotEvent> {
+    EVENTS.subscribe()
+}
+
+fn store_dir() -> PathBuf {
+    paths::workspace_root().join(STORE_DIR)
+}
+
+fn objects_dir() -> PathBuf {
+    store_dir().join("objects")
+}
+
+fn manifests_dir() -> PathBuf {
+    store_dir().join("manifests")
+}
+
+/// Takes a snapshot of the whole workspace and prunes old ones
+pub async fn take() -> Result<SnapshotInfo> {
+    let _guard = LOCK.lock().await;
+
+    let manifest = tokio::task::spawn_blocking(|| -> Result<Manifest> {
+        let previous = load_manifests()?.pop();
+        let manifest = snapshot_tree(previous.as_ref())?;
+        save_manifest(&manifest)?;
+        prune()?;
+        Ok(manifest)
+    })
+    .await??;
+
+    changes::mark_snapshot().await?;
+
+    let snapshot = SnapshotInfo::from(&manifest);
+    info!(id = snapshot.id, files = snapshot.files, "Took snapshot");
+    let _ = EVENTS.send(SnapshotEvent::Taken(snapshot.id.clone()));
+
+    Ok(snapshot)
+}
+
+/// Every snapshot still kept, oldest first
+pub async fn list() -> Result<Vec<SnapshotInfo>> {
+    let _guard = LOCK.lock().await;
+
+    let manifests = tokio::task::spawn_blocking(load_manifests).await??;
+    Ok(manifests.iter().map(SnapshotInfo::from).collect())
+}
+
+/// Restores `path` (a file or directory, or the whole workspace when empty) to
+/// how it was in snapshot `id`. Files under it that didn't exist back then are
+/// removed.
+pub async fn restore(id: &str, path: &str) -> Result<()> {
+    let prefix = paths::resolve(path)?.relative;
+    let _guard = LOCK.lock().await;
+
+    let id = id.to_string();
+    let restored = id.clone();
+    let backup = tokio::task::spawn_blocking(move || -> Result<Manifest> {
+        let manifest = load_manifest(&id)?;
+
+        // Snapshot what's about to be overwritten so the restore can be undone
+        let previous = load_manifests()?.pop();
+        let backup = snapshot_tree(previous.as_ref())?;
+        save_manifest(&backup)?;
+
+        restore_tree(&manifest, &prefix)?;
+        prune()?;
+        Ok(backup)
+    })
+    .await??;
+
+    info!(id = restored, path, backup = backup.id, "Restored snapshot");
+    let _ = EVENTS.send(SnapshotEvent::Restored(restored));
+
+    Ok(())
+}
+
+/// Paths that were added, modified or removed since the latest snapshot, or
+/// None if there isn't one yet
+pub async fn changed_since_latest() -> Result<Option<Vec<String>>> {
+    // Manifests are only ever renamed into place, so reading them doesn't need
+    // LOCK (which `take` is holding when it marks the change index)
+    tokio::task::spawn_blocking(|| -> Result<Option<Vec<String>>> {
+        let latest = match load_manifests()?.pop() {
+            Some(latest) => latest,
+            None => return Ok(None),
+        };
+
+        let root = paths::workspace_root();
+        let mut changed = vec![];
+        let mut seen = HashSet::new();
+
+        for (relative, metadata) in paths::walk("")? {
+            let unchanged = latest.files.get(&relative).is_some_and(|entry| {
+                entry.size == metadata.len()
+                    && (entry.modified == modified(&metadata)
+                        || matches!(hash_file(&root.join(&relative)), Ok(hash) if hash == entry.hash))
+            });
+            if !unchanged {
+                changed.push(relative.clone());
+            }
+            seen.insert(relative);
+        }
+
+        changed.extend(
+            latest
+                .files
+                .into_keys()
+                .filter(|relative| !seen.contains(relative)),
+        );
+        Ok(Some(changed))
+    })
+    .await?
+}
+
+fn now() -> (u64, u128) {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    (now.as_secs(), now.as_millis())
+}
+
+fn modified(metadata: &fs::Metadata) -> u128 {
+    metadata
+        .modified()
+        .ok()
+        .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+        .map(|time| time.as_nanos())
+        .unwrap_or_default()
+}
+
+fn mode(metadata: &fs::Metadata) -> u32 {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        metadata.permissions().mode() & 0o777
+    }
+
+    #[cfg(not(unix))]
+    if metadata.permissions().readonly() {
+        0o444
+    } else {
+        0o644
+    }
+}
+
+fn set_mode(path: &Path, mode: u32) -> io::Result<()> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(path, fs::Permissions::from_mode(mode))
+    }
+
+    #[cfg(not(unix))]
+    {
+        let mut permissions = fs::metadata(path)?.permissions();
+        permissions.set_readonly(mode & 0o222 == 0);
+        fs::set_permissions(path, permissions)
+    }
+}
+
+/// Regular files in the workspace (or under `prefix`), skipping ignored
+/// directories. Symlinks aren't followed or stored.
+fn hash_file(path: &Path) -> io::Result<String> {
+    let mut file = fs::File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0; 64 * 1024];
+
+    loop {
+        let read = file.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+    }
+
+    let mut hex = String::with_capacity(64);
+    for byte in hasher.finalize() {
+        hex.push_str(&format!("{:02x}", byte));
+    }
+    Ok(hex)
+}
+
+// What `hash_file` produces, lowercase hex sha256
+fn is_digest(hash: &str) -> bool {
+    hash.len() == 64
+        && hash
+            .bytes()
+            .all(|byte| byte.is_ascii_digit() || (b'a'..=b'f').contains(&byte))
+}
+
+fn snapshot_tree(previous: Option<&Manifest>) -> Result<Manifest> {
+    let root = paths::workspace_root();
+    let objects = objects_dir();
+    fs::create_dir_all(&objects)?;
+
+    let (created, millis) = now();
+    let mut files = BTreeMap::new();
+
+    for (relative, metadata) in paths::walk("")? {
+        let modified = modified(&metadata);
+        let unchanged = previous
+            .and_then(|previous| previous.files.get(&relative))
+            .filter(|entry| entry.size == metadata.len() && entry.modified == modified)
+            .filter(|entry| is_digest(&entry.hash) && objects.join(&entry.hash).exists());
+
+        let hash = match unchanged {
+            Some(entry) => entry.hash.clone(),
+            None => {
+                let absolute = root.join(&relative);
+                let hash = match hash_file(&absolute) {
+                    Ok(hash) => hash,
+                    // Removed while walking, it just isn't part of the snapshot
+                    Err(err) if err.kind() == io::ErrorKind::NotFound => continue,
+                    Err(err) => return Err(err.into()),
+                };
+
+                let object = objects.join(&hash);
+                if !object.exists() {
+                    let staging = objects.join(format!(".{}.homeval-snapshot", hash));
+                    fs::copy(&absolute, &staging)?;
+                    fs::rename(&staging, &object)?;
+                }
+                hash
+            }
+        };
+
+        files.insert(
+            relative,
+            FileEntry {
+                hash,
+                size: metadata.len(),
+                mode: mode(&metadata),
+                modified,
+            },
+        );
+    }
+
+    // Ids are creation times in milliseconds, bumped if two land in the same one
+    let id = match previous.and_then(|previous| previous.id.parse::<u128>().ok()) {
+        Some(last) if last >= millis => last + 1,
+        _ => millis,
+    };
+
+    Ok(Manifest {
+        id: id.to_string(),
+        created,
+        files,
+    })
+}
+
+fn save_manifest(manifest: &Manifest) -> Result<()> {
+    let dir = manifests_dir();
+    fs::create_dir_all(&dir)?;
+
+    let destination = dir.join(format!("{}.json", manifest.id));
+    let staging = dir.join(format!(".{}.json.homeval-snapshot", manifest.id));
+    fs::write(&staging, serde_json::to_vec(manifest)?)?;
+    fs::rename(staging, destination)?;
+    Ok(())
+}
+
+fn load_manifest(id: &str) -> Result<Manifest> {
+    if id.is_empty() || !id.chars().all(|c| c.is_ascii_digit()) {
+        return Err(format_err!("{}: no such snapshot", id));
+    }
+
+    match fs::read(manifests_dir().join(format!("{}.json", id))) {
+        Ok(contents) => Ok(serde_json::from_slice(&contents)?),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {
+            Err(format_err!("{}: no such snapshot", id))
+        }
+        Err(err) => Err(err.into()),
+    }
+}
+
+fn load_manifests() -> Result<Vec<Manifest>> {
+    let entries = match fs::read_dir(manifests_dir()) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(err) => return Err(err.into()),
+    };
+
+    let mut manifests = vec![];
+    for entry in entries {
+        let name = entry?.file_name();
+        let id = match name.to_str().and_then(|name| name.strip_suffix(".json")) {
+            Some(id) if !id.starts_with('.') => id.to_string(),
+            _ => continue,
+        };
+
+        match load_manifest(&id) {
+            Ok(manifest) => manifests.push(manifest),
+            Err(err) => warn!(%err, id, "Skipping unreadable snapshot"),
+        }
+    }
+
+    manifests.sort_by_key(|manifest| manifest.id.parse::<u128>().unwrap_or_default());
+    Ok(manifests)
+}
+
+// Applies the retention policy, then drops objects no snapshot uses anymore
+fn prune() -> Result<()> {
+    let mut manifests = load_manifests()?;
+    let excess = manifests.len().saturating_sub(*RETENTION);
+
+    for manifest in manifests.drain(..excess) {
+        debug!(id = manifest.id, "Pruning snapshot");
+        fs::remove_file(manifests_dir().join(format!("{}.json", manifest.id)))?;
+    }
+
+    let referenced: HashSet<&str> = manifests
+        .iter()
+        .flat_map(|manifest| manifest.files.values().map(|file| file.hash.as_str()))
+        .collect();
+
+    for entry in fs::read_dir(objects_dir())? {
+        let entry = entry?;
+        let name = entry.file_name();
+        if !name.to_str().is_some_and(|name| referenced.contains(name)) {
+            fs::remove_file(entry.path())?;
+        }
+    }
+
+    Ok(())
+}
+
+fn restore_tree(manifest: &Manifest, prefix: &str) -> Result<()> {
+    let root = paths::workspace_root();
+    let objects = objects_dir();
+
+    let wanted: BTreeMap<&String, &FileEntry> = manifest
+        .files
+        .iter()
+        .filter(|(path, _)| paths::is_under(path, prefix))
+        .collect();
+
+    if wanted.is_empty() && !prefix.is_empty() {
+        return Err(format_err!(
+            "{}: not part of snapshot {}",
+            prefix,
+            manifest.id
+        ));
+    }
+
+    for (relative, _) in paths::walk(prefix)? {
+        if !wanted.contains_key(&relative) {
+            debug!(path = relative, "Removing file created after snapshot");
+            fs::remove_file(root.join(&relative))?;
+        }
+    }
+
+    for (relative, entry) in wanted {
+        let destination = root.join(relative);
+
+        // Manifests are plain files in the workspace, don't trust them to stay inside it
+        if paths::resolve(relative).is_err() {
+            return Err(PathEscape(relative.clone()).into());
+        }
+
+        // Hashes are joined onto the objects directory, so they can't be paths
+        if !is_digest(&entry.hash) {
+            return Err(format_err!(
+                "{}: invalid object hash in snapshot {}",
+                relative,
+                manifest.id
+            ));
+        }
+
+        if matches!(hash_file(&destination), Ok(hash) if hash == entry.hash) {
+            continue;
+        }
+
+        if let Some(parent) = destination.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        let staging = destination.with_file_name(format!(
+            ".{}.homeval-restore",
+            destination
+                .file_name()
+                .and_then(|name| name.to_str())
+                .unwrap_or_default()
+        ));
+        fs::copy(objects.join(&entry.hash), &staging)?;
+        set_mode(&staging, entry.mode)?;
+        fs::rename(&staging, &destination)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_sha256_hex_is_a_digest() {
+        let digest = "ab".repeat(32);
+        assert!(is_digest(&digest));
+        assert!(!is_digest(&digest.to_uppercase()));
+        assert!(!is_digest(&digest[..62]));
+        assert!(!is_digest(&format!("../{}", &digest[3..])));
+        assert!(!is_digest(""));
+    }
+}
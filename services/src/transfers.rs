@@ -0,0 +1,371 @@
+use crate::{atomic::staging_path, paths, secrets, PathEscape};
+use anyhow::Result;
+use std::{
+    collections::HashMap,
+    io::Cursor,
+    path::PathBuf,
+    sync::atomic::{AtomicU64, Ordering},
+};
+use tokio::{
+    fs,
+    io::{AsyncReadExt, AsyncWriteExt},
+};
+use tracing::{debug, warn};
+
+static MAX_TRANSFER: AtomicU64 = AtomicU64::new(0);
+
+// Size of the chunks handed out for downloads
+const CHUNK_SIZE: usize = 256 * 1024;
+
+enum Direction {
+    Upload {
+        destination: PathBuf,
+        size: u64,
+        written: u64,
+    },
+    Download,
+}
+
+// Secrets are kept in memory so they never end up on disk
+enum Contents {
+    File(fs::File),
+    Secrets(Cursor<Vec<u8>>),
+}
+
+impl Contents {
+    async fn read(&mut self, buffer: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            Contents::File(file) => file.read(buffer).await,
+            Contents::Secrets(secrets) => secrets.read(buffer).await,
+        }
+    }
+
+    async fn write_all(&mut self, buffer: &[u8]) -> std::io::Result<()> {
+        match self {
+            Contents::File(file) => file.write_all(buffer).await,
+            Contents::Secrets(secrets) => secrets.write_all(buffer).await,
+        }
+    }
+}
+
+struct Transfer {
+    session: i32,
+    contents: Contents,
+    // The file being staged before it's renamed into place for uploads
+    staging: Option<PathBuf>,
+    hasher: crc32fast::Hasher,
+    direction: Direction,
+}
+
+/// In progress chunked uploads and downloads for a gcsfiles channel.
+///
+/// Uploads start with `transferStart { path, size }` and are answered with
+/// `transfer { id }`, followed by any amount of `transferChunk`s and finished
+/// with `transferComplete { id, crc32 }` which moves the file into place if
+/// the size and checksum match.
+///
+/// Downloads start with `transferStart { path, size: -1 }`, each `transfer { id }`
+/// is then answered with the next `transferChunk` until the file is exhausted,
+/// at which point `transferComplete` carries the checksum of the whole file.
+#[derive(Default)]
+pub struct Transfers {
+    transfers: HashMap<String, Transfer>,
+}
+
+fn next_id(channel: i32) -> String {
+    format!(
+        "{}-{}",
+        channel,
+        MAX_TRANSFER.fetch_add(1, Ordering::SeqCst)
+    )
+}
+
+impl Transfers {
+    pub async fn start(
+        &mut self,
+        channel: i32,
+        session: i32,
+        start: goval::TransferStart,
+    ) -> Result<goval::command::Body> {
+        let resolved = paths::resolve(&start.path)?;
+        let id = next_id(channel);
+
+        let transfer = if start.size < 0 && secrets::is_secret(&resolved) {
+            Transfer {
+                session,
+                contents: Contents::Secrets(Cursor::new(secrets::read_dotenv().await?)),
+                staging: None,
+                hasher: crc32fast::Hasher::new(),
+                direction: Direction::Download,
+            }
+        } else if start.size < 0 {
+            let file = match fs::File::open(&resolved.absolute).await {
+                Ok(file) => file,
+                Err(err) => {
+                    warn!(%err, path = start.path, "Error opening file for download");
+                    return Ok(goval::command::Body::Error(format!(
+                        "{}: no such file or directory",
+                        start.path
+                    )));
+                }
+            };
+
+            Transfer {
+                session,
+                contents: Contents::File(file),
+                staging: None,
+                hasher: crc32fast::Hasher::new(),
+                direction: Direction::Download,
+            }
+        } else if secrets::is_secret(&resolved) {
+            Transfer {
+                session,
+                contents: Contents::Secrets(Cursor::default()),
+                staging: None,
+                hasher: crc32fast::Hasher::new(),
+                direction: Direction::Upload {
+                    destination: resolved.absolute,
+                    size: start.size as u64,
+                    written: 0,
+                },
+            }
+        } else {
+            if resolved.relative.is_empty() {
+                return Err(PathEscape(start.path).into());
+            }
+
+            if let Some(parent) = resolved.absolute.parent() {
+                fs::create_dir_all(parent).await?;
+            }
+
+            let staging = staging_path(&resolved.absolute, &format!("transfer-{}", id))?;
+            let file = fs::File::create(&staging).await?;
+
+            Transfer {
+                session,
+                contents: Contents::File(file),
+                staging: Some(staging),
+                hasher: crc32fast::Hasher::new(),
+                direction: Direction::Upload {
+                    destination: resolved.absolute,
+                    size: start.size as u64,
+                    written: 0,
+                },
+            }
+        };
+
+        debug!(id, path = start.path, size = start.size, "Started transfer");
+        self.transfers.insert(id.clone(), transfer);
+
+        Ok(goval::command::Body::Transfer(goval::Transfer { id }))
+    }
+
+    pub async fn chunk(
+        &mut self,
+        session: i32,
+        chunk: goval::TransferChunk,
+    ) -> Result<goval::command::Body> {
+        let transfer = match self.owned(&chunk.id, session) {
+            Ok(transfer) => transfer,
+            Err(body) => return Ok(body),
+        };
+
+        let (size, written) = match &mut transfer.direction {
+            Direction::Upload { size, written, .. } => (*size, written),
+            Direction::Download => {
+                return Ok(goval::command::Body::Error(format!(
+                    "{}: can't upload chunks to a download",
+                    chunk.id
+                )))
+            }
+        };
+
+        let total = *written + chunk.content.len() as u64;
+        if total > size {
+            self.discard(&chunk.id).await;
+            return Ok(goval::command::Body::Error(format!(
+                "{}: transfer is larger than the {} bytes announced",
+                chunk.id, size
+            )));
+        }
+
+        transfer.contents.write_all(&chunk.content).await?;
+        transfer.hasher.update(&chunk.content);
+        *written = total;
+
+        Ok(goval::command::Body::Ok(goval::Ok {}))
+    }
+
+    pub async fn complete(
+        &mut self,
+        session: i32,
+        complete: goval::TransferComplete,
+    ) -> Result<goval::command::Body> {
+        if let Err(body) = self.owned(&complete.id, session) {
+            return Ok(body);
+        }
+        let mut transfer = match self.transfers.remove(&complete.id) {
+            Some(transfer) => transfer,
+            None => return Ok(unknown(&complete.id)),
+        };
+
+        let (destination, size, written) = match &transfer.direction {
+            Direction::Upload {
+                destination,
+                size,
+                written,
+            } => (destination.clone(), *size, *written),
+            Direction::Download => return Ok(goval::command::Body::Ok(goval::Ok {})),
+        };
+
+        let crc32 = transfer.hasher.clone().finalize();
+        let problem = if written != size {
+            Some(format!(
+                "{}: expected {} bytes but got {}",
+                complete.id, size, written
+            ))
+        } else if crc32 != complete.crc32 {
+            Some(format!(
+                "{}: crc32 mismatch, expected {} but got {}",
+                complete.id, complete.crc32, crc32
+            ))
+        } else {
+            None
+        };
+
+        if let Some(problem) = problem {
+            warn!(problem, "Transfer failed verification");
+            transfer.cleanup().await;
+            return Ok(goval::command::Body::Error(problem));
+        }
+
+        let stored = match &mut transfer.contents {
+            Contents::Secrets(secrets) => secrets::write_dotenv(secrets.get_ref()).await,
+            Contents::File(file) => {
+                async {
+                    file.flush().await?;
+                    file.sync_all().await?;
+                    if let Some(staging) = &transfer.staging {
+                        fs::rename(staging, destination).await?;
+                    }
+                    Ok(())
+                }
+                .await
+            }
+        };
+
+        if let Err(err) = stored {
+            transfer.cleanup().await;
+            return Err(err);
+        }
+
+        debug!(id = complete.id, size, "Completed transfer");
+        Ok(goval::command::Body::Ok(goval::Ok {}))
+    }
+
+    /// Hands out the next chunk of a download
+    pub async fn pull(
+        &mut self,
+        session: i32,
+        request: goval::Transfer,
+    ) -> Result<goval::command::Body> {
+        let transfer = match self.owned(&request.id, session) {
+            Ok(transfer) => transfer,
+            Err(body) => return Ok(body),
+        };
+
+        if let Direction::Upload { .. } = transfer.direction {
+            return Ok(goval::command::Body::Error(format!(
+                "{}: can't download from an upload",
+                request.id
+            )));
+        }
+
+        let mut content = vec![0; CHUNK_SIZE];
+        let read = transfer.contents.read(&mut content).await?;
+
+        if read == 0 {
+            let crc32 = transfer.hasher.clone().finalize();
+            if let Some(transfer) = self.transfers.remove(&request.id) {
+                transfer.cleanup().await;
+            }
+            return Ok(goval::command::Body::TransferComplete(
+                goval::TransferComplete {
+                    id: request.id,
+                    crc32,
+                },
+            ));
+        }
+
+        content.truncate(read);
+        transfer.hasher.update(&content);
+
+        Ok(goval::command::Body::TransferChunk(goval::TransferChunk {
+            id: request.id,
+            content,
+        }))
+    }
+
+    pub async fn cancel(&mut self, session: i32, id: &str) -> goval::command::Body {
+        if let Err(body) = self.owned(id, session) {
+            return body;
+        }
+
+        self.discard(id).await;
+        goval::command::Body::Ok(goval::Ok {})
+    }
+
+    // Transfers can only be continued by the session that started them
+    fn owned(&mut self, id: &str, session: i32) -> Result<&mut Transfer, goval::command::Body> {
+        match self.transfers.get_mut(id) {
+            Some(transfer) if transfer.session == session => Ok(transfer),
+            Some(_) => Err(goval::command::Body::Error(format!(
+                "{}: transfer was started by another session",
+                id
+            ))),
+            None => Err(unknown(id)),
+        }
+    }
+
+    async fn discard(&mut self, id: &str) {
+        if let Some(transfer) = self.transfers.remove(id) {
+            debug!(id, "Cancelled transfer");
+            transfer.cleanup().await;
+        }
+    }
+
+    /// Drops every transfer started by `session`
+    pub async fn detach(&mut self, session: i32) {
+        let ids: Vec<String> = self
+            .transfers
+            .iter()
+            .filter(|(_, transfer)| transfer.session == session)
+            .map(|(id, _)| id.clone())
+            .collect();
+
+        for id in ids {
+            self.discard(&id).await;
+        }
+    }
+
+    pub async fn shutdown(&mut self) {
+        for (_, transfer) in self.transfers.drain() {
+            transfer.cleanup().await;
+        }
+    }
+}
+
+impl Transfer {
+    async fn cleanup(self) {
+        drop(self.contents);
+        if let Some(staging) = self.staging {
+            if let Err(err) = fs::remove_file(&staging).await {
+                warn!(%err, ?staging, "Error removing staged transfer");
+            }
+        }
+    }
+}
+
+fn unknown(id: &str) -> goval::command::Body {
+    goval::command::Body::Error(format!("{}: unknown transfer", id))
+}
@@ -0,0 +1,127 @@
+use super::paths::{self, PathEscape};
+use anyhow::{format_err, Result};
+use std::{
+    path::{Component, Path, PathBuf},
+    sync::atomic::{AtomicU64, Ordering},
+};
+use tokio::{fs, io::AsyncWriteExt};
+use tracing::warn;
+
+static MAX_STAGED: AtomicU64 = AtomicU64::new(0);
+
+/// Temp file next to `destination`, so it can be renamed over it atomically.
+/// Refuses anything that isn't inside the workspace, like the root itself,
+/// since the temp file would end up outside of it.
+pub fn staging_path(destination: &Path, tag: &str) -> Result<PathBuf> {
+    // Relative paths are relative to the workspace, like everything else
+    let absolute = paths::workspace_root().join(destination);
+    let escapes = absolute
+        .components()
+        .any(|component| component == Component::ParentDir);
+    match absolute.parent() {
+        Some(parent) if parent.starts_with(paths::workspace_root()) && !escapes => {}
+        _ => return Err(PathEscape(destination.display().to_string()).into()),
+    }
+
+    let name = destination
+        .file_name()
+        .and_then(|name| name.to_str())
+        .ok_or_else(|| format_err!("Can't stage a file without a name"))?;
+
+    Ok(destination.with_file_name(format!(".{}.homeval-{}", name, tag)))
+}
+
+/// Whether `name` is a temp file made by [`staging_path`]
+pub fn is_staging_name(name: &str) -> bool {
+    name.starts_with('.') && name.contains(".homeval-")
+}
+
+/// Writes `content` to a temp file, syncs it and renames it over `destination`
+/// so readers only ever see the old or the new contents. Permissions of an
+/// existing destination are kept.
+pub async fn write_atomic(destination: &Path, content: &[u8]) -> Result<()> {
+    // Write through symlinks instead of replacing them with a regular file
+    let destination = fs::canonicalize(destination)
+        .await
+        .unwrap_or_else(|_| destination.to_path_buf());
+
+    let staging = staging_path(
+        &destination,
+        &format!("write-{}", MAX_STAGED.fetch_add(1, Ordering::SeqCst)),
+    )?;
+
+    let result = async {
+        let mut file = fs::File::create(&staging).await?;
+        if let Ok(existing) = fs::metadata(&destination).await {
+            file.set_permissions(existing.permissions()).await?;
+        }
+
+        file.write_all(content).await?;
+        file.sync_all().await?;
+        drop(file);
+
+        fs::rename(&staging, &destination).await?;
+        sync_parent(&destination).await;
+        Ok(())
+    }
+    .await;
+
+    if result.is_err() {
+        if let Err(err) = fs::remove_file(&staging).await {
+            warn!(%err, ?staging, "Error removing staged write");
+        }
+    }
+
+    result
+}
+
+/// Flushes a file (or directory) and the directory containing it to disk
+pub async fn persist(path: &Path) -> Result<()> {
+    fs::File::open(path).await?.sync_all().await?;
+    sync_parent(path).await;
+    Ok(())
+}
+
+// Makes the rename itself durable, not supported everywhere so errors are ignored
+async fn sync_parent(path: &Path) {
+    if let Some(parent) = path.parent() {
+        if let Ok(dir) = fs::File::open(parent).await {
+            let _ = dir.sync_all().await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stages_next_to_the_destination() {
+        let destination = paths::workspace_root().join("dir").join("file.txt");
+        let staging = staging_path(&destination, "test").unwrap();
+        assert_eq!(staging.parent(), destination.parent());
+        assert!(is_staging_name(
+            staging.file_name().unwrap().to_str().unwrap()
+        ));
+    }
+
+    #[test]
+    fn stages_relative_paths_in_the_workspace() {
+        let staging = staging_path(Path::new("file.txt"), "test").unwrap();
+        assert_eq!(staging.parent(), Some(Path::new("")));
+    }
+
+    #[test]
+    fn refuses_the_workspace_root() {
+        let root = paths::workspace_root();
+        assert!(staging_path(root, "test").is_err());
+    }
+
+    #[test]
+    fn refuses_paths_outside_the_workspace() {
+        let outside = paths::workspace_root().parent().unwrap().join("file.txt");
+        assert!(staging_path(&outside, "test").is_err());
+        assert!(staging_path(Path::new("../file.txt"), "test").is_err());
+        assert!(staging_path(Path::new("dir/../../file.txt"), "test").is_err());
+    }
+}
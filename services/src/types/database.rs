@@ -0,0 +1,4 @@
+use tokio::sync::OnceCell;
+
+/// Set by homeval once it has connected to `$HOMEVAL_DB` and run migrations
+pub static DATABASE: OnceCell<sea_orm::DatabaseConnection> = OnceCell::const_new();
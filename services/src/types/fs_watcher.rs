@@ -1,24 +1,43 @@
 use notify_debouncer_full::{
     new_debouncer,
     notify::{self, event::ModifyKind, Event, EventKind, RecommendedWatcher, Watcher},
-    DebounceEventResult, Debouncer,
+    DebounceEventResult, Debouncer, FileIdCache, FileIdMap,
 };
 use serde::Serialize;
-use tracing::error;
+use tracing::{debug, error, warn};
 
-use anyhow::Result;
+use anyhow::{format_err, Result};
 
-use std::{path::Path, time::Duration};
+use std::{
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        LazyLock, Mutex,
+    },
+    time::Duration,
+};
+
+use crate::{paths, ChannelMessage};
 
-use crate::ChannelMessage;
+// How long to wait for a burst of changes to settle before reporting it
+static DEBOUNCE: LazyLock<Duration> = LazyLock::new(|| {
+    Duration::from_millis(
+        std::env::var("HOMEVAL_FS_DEBOUNCE_MS")
+            .ok()
+            .and_then(|ms| ms.parse().ok())
+            .unwrap_or(1000),
+    )
+});
 
-// static FILE_WATCHER_MAP: LazyLock<
-//     RwLock<HashMap<u32, Arc<Mutex<Debouncer<RecommendedWatcher, FileIdMap>>>>>,
-// > = LazyLock::new(|| RwLock::new(HashMap::new()));
-// static FILE_WATCHER_MESSAGES: LazyLock<
-//     RwLock<HashMap<u32, Arc<deadqueue::unlimited::Queue<FSEvent>>>>,
-// > = LazyLock::new(|| RwLock::new(HashMap::new()));
-// static MAX_WATCHER: LazyLock<Mutex<u32>> = LazyLock::new(|| Mutex::new(0));
+// One watcher on the workspace for the whole process, started by the first
+// subscriber. Events are fanned out to every `FSWatcher` whose paths they
+// touch. Locked from blocking threads while directories are added.
+static DEBOUNCER: LazyLock<Mutex<Option<Debouncer<RecommendedWatcher, FileIdMap>>>> =
+    LazyLock::new(|| Mutex::new(None));
+static STARTED: tokio::sync::OnceCell<()> = tokio::sync::OnceCell::const_new();
+// Locked from the debouncer thread, so it can't be a tokio mutex
+static SUBSCRIBERS: LazyLock<Mutex<Vec<Subscriber>>> = LazyLock::new(|| Mutex::new(vec![]));
+static MAX_SUBSCRIBER: AtomicU64 = AtomicU64::new(0);
 
 #[derive(Serialize, Debug, Clone)]
 #[serde(rename_all = "camelCase")]
@@ -27,96 +46,245 @@ pub enum FSEvent {
     Create(String),
     Modify(String),
     Rename(String, String),
+    /// Events were dropped (the kernel queue overflowed), anything watched
+    /// might have changed
+    Rescan,
     Err(String),
 }
 
+struct Subscriber {
+    id: u64,
+    writer: tokio::sync::mpsc::UnboundedSender<ChannelMessage>,
+    files: Vec<String>,
+    prefixes: Vec<String>,
+}
+
+impl Subscriber {
+    fn wants(&self, event: &FSEvent) -> bool {
+        let matches = |path: &str| {
+            self.files.iter().any(|file| file == path)
+                || self
+                    .prefixes
+                    .iter()
+                    .any(|prefix| paths::is_under(path, prefix))
+        };
+
+        match event {
+            FSEvent::Remove(path) | FSEvent::Create(path) | FSEvent::Modify(path) => matches(path),
+            FSEvent::Rename(from, to) => matches(from) || matches(to),
+            FSEvent::Rescan | FSEvent::Err(_) => true,
+        }
+    }
+}
+
+/// A channel's subscription to the shared workspace watcher, events for the
+/// paths it watches are delivered as `ChannelMessage::FSEvent`.
 pub struct FSWatcher {
-    debouncer: Debouncer<RecommendedWatcher, notify_debouncer_full::FileIdMap>,
-    writer: tokio::sync::mpsc::UnboundedSender<super::ChannelMessage>,
+    id: u64,
 }
 
 impl FSWatcher {
     pub async fn new(
         writer: tokio::sync::mpsc::UnboundedSender<super::ChannelMessage>,
     ) -> Result<FSWatcher> {
-        // let (writer, reader) = broadcast::channel::<FSEvent>(5);
-
-        // FILE_WATCHER_MESSAGES
-        //     .write()
-        //     .await
-        //     .insert(watcher_id, queue.clone());
-
-        // tokio::spawn(async move {
-        let debounce_writer = writer.clone();
-        let debouncer = tokio::task::spawn_blocking(move || {
-            new_debouncer(
-                Duration::from_secs(1),
-                None,
-                move |result: DebounceEventResult| match result {
-                    Ok(events) => events.iter().for_each(|event| {
-                        if let Some(final_event) = notify_event_to_final(event).unwrap() {
-                            debounce_writer
-                                .send(ChannelMessage::FSEvent(final_event))
-                                .expect("TODO: handle this");
-                        }
-                    }),
-                    Err(errors) => errors.iter().for_each(|error| {
-                        error!(?error, "Error in debouncer");
-                        debounce_writer
-                            .send(ChannelMessage::FSEvent(FSEvent::Err(error.to_string())))
-                            .expect("TODO: handle this");
-                    }),
-                },
-            )
-
-            // let mut watcher_map = FILE_WATCHER_MAP.blocking_write();
-            // watcher_map.insert(watcher_id, Arc::new(Mutex::new(debouncer)));
-        })
-        .await??;
-        // });
-
-        Ok(FSWatcher { debouncer, writer })
+        start().await?;
+
+        let id = MAX_SUBSCRIBER.fetch_add(1, Ordering::SeqCst);
+        SUBSCRIBERS.lock().unwrap().push(Subscriber {
+            id,
+            writer,
+            files: vec![],
+            prefixes: vec![],
+        });
+
+        Ok(FSWatcher { id })
     }
 
+    /// Watches individual files, as paths relative to the workspace root
     pub async fn watch(&mut self, files: Vec<String>) -> Result<()> {
-        for file in files {
-            let path = Path::new(&file);
-            self.debouncer
-                .watcher()
-                .watch(path, notify::RecursiveMode::NonRecursive)?;
-            self.debouncer
-                .cache()
-                .add_root(path, notify::RecursiveMode::NonRecursive)
-        }
+        self.update(|subscriber| subscriber.files.extend(files));
+        Ok(())
+    }
 
+    pub async fn unwatch(&mut self, files: &[String]) -> Result<()> {
+        self.update(|subscriber| subscriber.files.retain(|file| !files.contains(file)));
+        Ok(())
+    }
+
+    /// Watches everything under `prefix`, an empty prefix is the whole workspace
+    pub async fn watch_prefix(&mut self, prefix: &str) -> Result<()> {
+        let prefix = prefix.trim_end_matches('/').to_string();
+        self.update(|subscriber| subscriber.prefixes.push(prefix));
         Ok(())
     }
 
     pub async fn shutdown(self) {
-        self.debouncer.stop_nonblocking();
-        drop(self.writer)
+        SUBSCRIBERS
+            .lock()
+            .unwrap()
+            .retain(|subscriber| subscriber.id != self.id);
+    }
+
+    fn update(&self, apply: impl FnOnce(&mut Subscriber)) {
+        if let Some(subscriber) = SUBSCRIBERS
+            .lock()
+            .unwrap()
+            .iter_mut()
+            .find(|subscriber| subscriber.id == self.id)
+        {
+            apply(subscriber)
+        }
     }
 }
 
-fn notify_event_to_final(event: &Event) -> Result<Option<FSEvent>> {
-    let base = std::env::current_dir()?;
-    let file_name = event.paths[0]
-        .strip_prefix(base.clone())?
+async fn start() -> Result<()> {
+    STARTED.get_or_try_init(start_debouncer).await?;
+    Ok(())
+}
+
+async fn start_debouncer() -> Result<()> {
+    let root = paths::workspace_root();
+    let runtime = tokio::runtime::Handle::current();
+    let mut started = tokio::task::spawn_blocking(move || {
+        new_debouncer(
+            *DEBOUNCE,
+            None,
+            move |result: DebounceEventResult| match result {
+                Ok(events) => events.iter().for_each(|event| {
+                    if let Some(dir) = new_directory(event) {
+                        runtime.spawn_blocking(move || watch_tree(dir));
+                    }
+
+                    match notify_event_to_final(event) {
+                        Ok(Some(final_event)) => dispatch(final_event),
+                        Ok(None) => {}
+                        Err(err) => warn!(%err, paths = ?event.paths, "Skipping fs event"),
+                    }
+                }),
+                Err(errors) => errors.iter().for_each(|error| {
+                    error!(?error, "Error in debouncer");
+                    dispatch(FSEvent::Err(error.to_string()))
+                }),
+            },
+        )
+    })
+    .await??;
+
+    // Each directory is cached on its own as it's watched
+    started
+        .cache()
+        .add_root(root, notify::RecursiveMode::NonRecursive);
+    *DEBOUNCER.lock().unwrap() = Some(started);
+
+    // Big workspaces take a while to walk, changes in what's already watched
+    // are reported meanwhile
+    tokio::task::spawn_blocking(move || {
+        watch_tree(root.to_path_buf());
+        debug!(?root, debounce = ?*DEBOUNCE, "Started workspace watcher");
+    });
+    Ok(())
+}
+
+// Watches every directory under `dir` on its own, a recursive watch can't
+// leave out `.git`, `node_modules` and the like
+fn watch_tree(dir: PathBuf) {
+    let mut pending = vec![dir];
+    while let Some(dir) = pending.pop() {
+        {
+            let mut debouncer = DEBOUNCER.lock().unwrap();
+            let debouncer = match debouncer.as_mut() {
+                Some(debouncer) => debouncer,
+                None => return,
+            };
+
+            if let Err(err) = debouncer
+                .watcher()
+                .watch(&dir, notify::RecursiveMode::NonRecursive)
+            {
+                warn!(%err, ?dir, "Error watching directory");
+                continue;
+            }
+            debouncer.cache().add_path(&dir);
+        }
+
+        let entries = match std::fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            Err(err) => {
+                debug!(%err, ?dir, "Error listing watched directory");
+                continue;
+            }
+        };
+        for entry in entries.flatten() {
+            let ignored = entry
+                .file_name()
+                .to_str()
+                .map_or(true, paths::is_always_ignored);
+            if !ignored && entry.file_type().is_ok_and(|kind| kind.is_dir()) {
+                pending.push(entry.path());
+            }
+        }
+    }
+}
+
+// A directory that appeared and has to be watched too
+fn new_directory(event: &Event) -> Option<PathBuf> {
+    let path = match event.kind {
+        EventKind::Create(_)
+        | EventKind::Modify(ModifyKind::Name(notify::event::RenameMode::To))
+        | EventKind::Modify(ModifyKind::Name(notify::event::RenameMode::Both)) => {
+            event.paths.last()?
+        }
+        _ => return None,
+    };
+
+    let name = path.file_name()?.to_str()?;
+    (path.is_dir() && !paths::is_always_ignored(name)).then(|| path.clone())
+}
+
+fn dispatch(event: FSEvent) {
+    // Subscribers whose channel is gone are dropped along the way
+    SUBSCRIBERS.lock().unwrap().retain(|subscriber| {
+        !subscriber.wants(&event)
+            || subscriber
+                .writer
+                .send(ChannelMessage::FSEvent(event.clone()))
+                .is_ok()
+    });
+}
+
+fn relative(path: &Path) -> Result<String> {
+    path.strip_prefix(paths::workspace_root())
+        .map_err(|_| format_err!("{:?}: path is outside of the workspace", path))?
         .to_str()
-        .unwrap()
-        .to_string();
+        .map(|path| path.to_string())
+        .ok_or_else(|| format_err!("{:?}: path is not valid utf-8", path))
+}
+
+fn notify_event_to_final(event: &Event) -> Result<Option<FSEvent>> {
+    if event.need_rescan() {
+        warn!("Filesystem events were dropped, asking subscribers to rescan");
+        return Ok(Some(FSEvent::Rescan));
+    }
+
+    if let EventKind::Modify(ModifyKind::Name(notify::event::RenameMode::Both)) = event.kind {
+        let from = event.paths.first().map(|path| relative(path));
+        let to = event.paths.get(1).map(|path| relative(path));
+        // A move from or to somewhere we can't represent is a create or remove
+        return match (from, to) {
+            (Some(Ok(from)), Some(Ok(to))) => Ok(Some(FSEvent::Rename(from, to))),
+            (Some(Ok(from)), _) => Ok(Some(FSEvent::Remove(from))),
+            (_, Some(Ok(to))) => Ok(Some(FSEvent::Create(to))),
+            (Some(Err(err)), _) => Err(err),
+            _ => Ok(None),
+        };
+    }
+
+    let file_name = match event.paths.first() {
+        Some(path) => relative(path)?,
+        None => return Ok(None),
+    };
     match event.kind {
         EventKind::Create(_) => Ok(Some(FSEvent::Create(file_name))),
-        EventKind::Modify(_kind @ ModifyKind::Name(notify::event::RenameMode::Both)) => {
-            Ok(Some(FSEvent::Rename(
-                file_name,
-                event.paths[1]
-                    .strip_prefix(base)?
-                    .to_str()
-                    .unwrap()
-                    .to_string(),
-            )))
-        }
         EventKind::Modify(_kind @ ModifyKind::Name(notify::event::RenameMode::From)) => {
             Ok(Some(FSEvent::Remove(file_name.to_string())))
         }
@@ -0,0 +1,216 @@
+use anyhow::{format_err, Result};
+use base64::{engine::general_purpose, Engine as _};
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, fmt};
+use tokio::sync::Mutex;
+use tracing::debug;
+
+use super::{atomic, paths};
+
+// Used when there's no database, lives next to the snapshots
+static META_FILE: &str = ".homeval/meta.json";
+
+// Every read-check-write goes through here so preconditions can't race. The
+// file backend's contents are cached in it once loaded.
+static STORE: Mutex<Option<HashMap<String, Meta>>> = Mutex::const_new(None);
+
+/// A value in the metadata store. `generation` changes whenever the data is
+/// replaced, `metageneration` whenever it's appended to and goes back to 1 for
+/// each new generation.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct Meta {
+    #[serde(with = "base64_bytes")]
+    pub data: Vec<u8>,
+    pub generation: i64,
+    pub metageneration: i64,
+}
+
+/// Returned (wrapped in an `anyhow::Error`) when a write's preconditions don't
+/// hold, the write isn't applied.
+#[derive(Debug)]
+pub struct PreconditionFailed(pub String);
+
+impl fmt::Display for PreconditionFailed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: precondition failed", self.0)
+    }
+}
+
+impl std::error::Error for PreconditionFailed {}
+
+pub async fn read(key: &str) -> Result<Option<Meta>> {
+    let mut store = STORE.lock().await;
+    load(&mut store, key).await
+}
+
+/// Replaces the data stored under `key`, starting a new generation
+pub async fn write(
+    key: &str,
+    data: Vec<u8>,
+    preconditions: Option<goval::Preconditions>,
+) -> Result<Meta> {
+    update(key, preconditions, |current| Meta {
+        data,
+        generation: current.map(|meta| meta.generation).unwrap_or_default() + 1,
+        metageneration: 1,
+    })
+    .await
+}
+
+/// Adds `data` to the end of what's stored under `key`, creating it if needed
+pub async fn append(
+    key: &str,
+    data: Vec<u8>,
+    preconditions: Option<goval::Preconditions>,
+) -> Result<Meta> {
+    update(key, preconditions, |current| match current {
+        Some(mut meta) => {
+            meta.data.extend(data);
+            meta.metageneration += 1;
+            meta
+        }
+        None => Meta {
+            data,
+            generation: 1,
+            metageneration: 1,
+        },
+    })
+    .await
+}
+
+async fn update(
+    key: &str,
+    preconditions: Option<goval::Preconditions>,
+    apply: impl FnOnce(Option<Meta>) -> Meta,
+) -> Result<Meta> {
+    if key.is_empty() {
+        return Err(format_err!("Metadata key can't be empty"));
+    }
+
+    let mut store = STORE.lock().await;
+    let current = load(&mut store, key).await?;
+
+    if let Some(preconditions) = preconditions {
+        check(key, &preconditions, current.as_ref())?;
+    }
+
+    let updated = apply(current);
+    save(&mut store, key, &updated).await?;
+
+    debug!(
+        key,
+        generation = updated.generation,
+        metageneration = updated.metageneration,
+        "Updated metadata"
+    );
+    Ok(updated)
+}
+
+// Zero means "don't care", like in GCS
+fn check(key: &str, preconditions: &goval::Preconditions, current: Option<&Meta>) -> Result<()> {
+    let failed = || Err(PreconditionFailed(key.to_string()).into());
+
+    match current {
+        Some(_) if preconditions.does_not_exist => failed(),
+        None if preconditions.generation != 0 || preconditions.metageneration != 0 => failed(),
+        Some(meta)
+            if (preconditions.generation != 0 && preconditions.generation != meta.generation)
+                || (preconditions.metageneration != 0
+                    && preconditions.metageneration != meta.metageneration) =>
+        {
+            failed()
+        }
+        _ => Ok(()),
+    }
+}
+
+async fn load(store: &mut Option<HashMap<String, Meta>>, key: &str) -> Result<Option<Meta>> {
+    #[cfg(feature = "database")]
+    if let Some(database) = super::DATABASE.get() {
+        use sea_orm::EntityTrait;
+
+        return Ok(entity::meta::Entity::find_by_id(key.to_string())
+            .one(database)
+            .await?
+            .map(|row| Meta {
+                data: row.data,
+                generation: row.generation,
+                metageneration: row.metageneration,
+            }));
+    }
+
+    Ok(file_store(store).await?.get(key).cloned())
+}
+
+async fn save(store: &mut Option<HashMap<String, Meta>>, key: &str, meta: &Meta) -> Result<()> {
+    #[cfg(feature = "database")]
+    if let Some(database) = super::DATABASE.get() {
+        use sea_orm::{sea_query::OnConflict, ActiveValue, EntityTrait};
+
+        let row = entity::meta::ActiveModel {
+            key: ActiveValue::Set(key.to_string()),
+            data: ActiveValue::Set(meta.data.clone()),
+            generation: ActiveValue::Set(meta.generation),
+            metageneration: ActiveValue::Set(meta.metageneration),
+        };
+
+        entity::meta::Entity::insert(row)
+            .on_conflict(
+                OnConflict::column(entity::meta::Column::Key)
+                    .update_columns([
+                        entity::meta::Column::Data,
+                        entity::meta::Column::Generation,
+                        entity::meta::Column::Metageneration,
+                    ])
+                    .to_owned(),
+            )
+            .exec(database)
+            .await?;
+        return Ok(());
+    }
+
+    // Only update the cache once it's on disk, so a failed write isn't served
+    let values = file_store(store).await?;
+    let mut updated = values.clone();
+    updated.insert(key.to_string(), meta.clone());
+
+    let path = paths::workspace_root().join(META_FILE);
+    if let Some(parent) = path.parent() {
+        tokio::fs::create_dir_all(parent).await?;
+    }
+    atomic::write_atomic(&path, &serde_json::to_vec(&updated)?).await?;
+
+    *values = updated;
+    Ok(())
+}
+
+async fn file_store(
+    store: &mut Option<HashMap<String, Meta>>,
+) -> Result<&mut HashMap<String, Meta>> {
+    if store.is_none() {
+        let values = match tokio::fs::read(paths::workspace_root().join(META_FILE)).await {
+            Ok(contents) => serde_json::from_slice(&contents)?,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => HashMap::new(),
+            Err(err) => return Err(err.into()),
+        };
+        *store = Some(values);
+    }
+
+    Ok(store.get_or_insert_with(HashMap::new))
+}
+
+mod base64_bytes {
+    use super::*;
+    use serde::{Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(data: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&general_purpose::STANDARD.encode(data))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        let encoded = String::deserialize(deserializer)?;
+        general_purpose::STANDARD
+            .decode(encoded)
+            .map_err(serde::de::Error::custom)
+    }
+}
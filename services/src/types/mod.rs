@@ -21,3 +21,20 @@ pub use config::dotreplit::DotReplit;
 
 pub mod proc;
 pub use proc::Proc;
+
+pub mod atomic;
+
+pub mod meta;
+
+#[cfg(feature = "database")]
+pub mod database;
+#[cfg(feature = "database")]
+pub use database::DATABASE;
+
+pub mod secrets;
+
+pub mod paths;
+pub use paths::{PathEscape, WorkspacePath};
+
+pub mod processes;
+pub use processes::stop_processes;
@@ -0,0 +1,271 @@
+use anyhow::{format_err, Result};
+use std::{
+    fmt,
+    path::{Component, Path, PathBuf},
+    sync::LazyLock,
+};
+use tracing::warn;
+
+// Everything clients can touch has to live under here, fs events are reported
+// relative to the same directory.
+static WORKSPACE_ROOT: LazyLock<PathBuf> = LazyLock::new(|| {
+    let root = std::env::current_dir().expect("Couldn't read the current directory");
+    root.canonicalize().unwrap_or(root)
+});
+
+/// A client supplied path that has been checked to stay inside the workspace.
+#[derive(Clone, Debug)]
+pub struct WorkspacePath {
+    /// Absolute path to use for filesystem operations
+    pub absolute: PathBuf,
+    /// Normalised path relative to the workspace root, as clients see it
+    pub relative: String,
+}
+
+/// Returned (wrapped in an `anyhow::Error`) when a path resolves outside the
+/// workspace, channels turn it into an `Error` reply for the client.
+#[derive(Debug)]
+pub struct PathEscape(pub String);
+
+impl fmt::Display for PathEscape {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: path is outside of the workspace", self.0)
+    }
+}
+
+impl std::error::Error for PathEscape {}
+
+/// Where homeval keeps its own state, clients can't see or touch it
+pub const STATE_DIR: &str = ".homeval";
+
+// Directories that are never reported to clients, tracked for changes or
+// snapshotted
+static ALWAYS_IGNORED: &[&str] = &[".git", "node_modules", STATE_DIR];
+
+/// Whether a file or directory named `name` is internal or too noisy to report
+pub fn is_always_ignored(name: &str) -> bool {
+    ALWAYS_IGNORED.contains(&name) || super::atomic::is_staging_name(name)
+}
+
+/// Whether the relative `path` is `prefix` or inside it, an empty prefix is
+/// the whole workspace
+pub fn is_under(path: &str, prefix: &str) -> bool {
+    prefix.is_empty()
+        || path == prefix
+        || path
+            .strip_prefix(prefix)
+            .is_some_and(|rest| rest.starts_with('/'))
+}
+
+/// Every regular file under the relative `prefix` (or `prefix` itself if it's
+/// one) with its metadata. Ignored directories are skipped and symlinks aren't
+/// followed. Blocks, so run it off the runtime.
+pub fn walk(prefix: &str) -> Result<Vec<(String, std::fs::Metadata)>> {
+    let root = workspace_root();
+    let mut found = vec![];
+    let mut pending = vec![prefix.to_string()];
+
+    while let Some(relative) = pending.pop() {
+        let metadata = match std::fs::symlink_metadata(root.join(&relative)) {
+            Ok(metadata) => metadata,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(err) => return Err(err.into()),
+        };
+
+        if metadata.is_file() {
+            found.push((relative, metadata));
+        } else if metadata.is_dir() {
+            for entry in std::fs::read_dir(root.join(&relative))? {
+                let name = match entry?.file_name().into_string() {
+                    Ok(name) => name,
+                    Err(name) => {
+                        warn!(?name, "Skipping file with a non utf-8 name");
+                        continue;
+                    }
+                };
+                if is_always_ignored(&name) {
+                    continue;
+                }
+
+                pending.push(if relative.is_empty() {
+                    name
+                } else {
+                    format!("{}/{}", relative, name)
+                });
+            }
+        }
+    }
+
+    Ok(found)
+}
+
+pub fn workspace_root() -> &'static Path {
+    &WORKSPACE_ROOT
+}
+
+pub fn resolve(path: &str) -> Result<WorkspacePath> {
+    resolve_in(workspace_root(), path)
+}
+
+fn resolve_in(root: &Path, path: &str) -> Result<WorkspacePath> {
+    let escape = || PathEscape(path.to_string());
+
+    let requested = Path::new(path);
+    let requested = if requested.is_absolute() {
+        requested.strip_prefix(root).map_err(|_| escape())?
+    } else {
+        requested
+    };
+
+    // Normalise lexically first so `..` can't climb out of the root
+    let mut relative = PathBuf::new();
+    for component in requested.components() {
+        match component {
+            Component::Normal(part) => relative.push(part),
+            Component::CurDir => {}
+            Component::ParentDir => {
+                if !relative.pop() {
+                    return Err(escape().into());
+                }
+            }
+            Component::RootDir | Component::Prefix(_) => return Err(escape().into()),
+        }
+    }
+
+    if relative.starts_with(STATE_DIR) {
+        return Err(escape().into());
+    }
+
+    let absolute = root.join(&relative);
+
+    // Then make sure no symlink along the way points outside of the root or
+    // into homeval's state, the path itself might not exist yet so check the
+    // closest existing ancestor.
+    let mut existing = absolute.as_path();
+    loop {
+        match existing.canonicalize() {
+            Ok(canonical) => {
+                if !canonical.starts_with(root) || canonical.starts_with(root.join(STATE_DIR)) {
+                    return Err(escape().into());
+                }
+                break;
+            }
+            // Dangling symlinks can't be followed to check where they lead,
+            // writing through one could create a file anywhere.
+            Err(_) if existing.symlink_metadata().is_ok() => return Err(escape().into()),
+            Err(_) => match existing.parent() {
+                Some(parent) => existing = parent,
+                None => break,
+            },
+        }
+    }
+
+    let relative = match relative.to_str() {
+        Some(relative) => relative.to_string(),
+        None => return Err(format_err!("{}: path is not valid utf-8", path)),
+    };
+
+    Ok(WorkspacePath { absolute, relative })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    // A scratch workspace, with `outside` next to it to escape to
+    fn workspace(name: &str) -> PathBuf {
+        let base =
+            std::env::temp_dir().join(format!("homeval-paths-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&base);
+        fs::create_dir_all(base.join("root").join("dir")).unwrap();
+        fs::create_dir_all(base.join("outside")).unwrap();
+        base.canonicalize().unwrap().join("root")
+    }
+
+    fn escapes(root: &Path, path: &str) -> bool {
+        resolve_in(root, path).is_err_and(|err| err.is::<PathEscape>())
+    }
+
+    #[test]
+    fn prefixes() {
+        assert!(is_under("src/main.rs", ""));
+        assert!(is_under("src", "src"));
+        assert!(is_under("src/main.rs", "src"));
+        assert!(!is_under("srcs/main.rs", "src"));
+        assert!(!is_under("src", "src/main.rs"));
+    }
+
+    #[test]
+    fn parent_dirs_stay_inside() {
+        let root = workspace("parent");
+        assert_eq!(resolve_in(&root, "dir/../file").unwrap().relative, "file");
+        assert!(escapes(&root, ".."));
+        assert!(escapes(&root, "../outside"));
+        assert!(escapes(&root, "dir/../../outside/file"));
+    }
+
+    #[test]
+    fn absolute_paths() {
+        let root = workspace("absolute");
+        let inside = root.join("dir").join("file");
+        let resolved = resolve_in(&root, inside.to_str().unwrap()).unwrap();
+        assert_eq!(resolved.relative, "dir/file");
+        assert_eq!(resolved.absolute, inside);
+
+        let outside = root.parent().unwrap().join("outside");
+        assert!(escapes(&root, outside.to_str().unwrap()));
+        assert!(escapes(&root, "/etc/passwd"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlinks_out_of_the_root() {
+        use std::os::unix::fs::symlink;
+
+        let root = workspace("symlink");
+        symlink(root.parent().unwrap().join("outside"), root.join("out")).unwrap();
+        symlink(root.join("dir"), root.join("in")).unwrap();
+        symlink(root.join("missing"), root.join("dangling")).unwrap();
+
+        assert!(escapes(&root, "out"));
+        // Doesn't exist yet, but would be created outside
+        assert!(escapes(&root, "out/new/file"));
+        assert!(escapes(&root, "dangling"));
+        assert_eq!(resolve_in(&root, "in/file").unwrap().relative, "in/file");
+    }
+
+    #[test]
+    fn root_itself() {
+        let root = workspace("root");
+        for path in ["", ".", "./", "dir/.."] {
+            let resolved = resolve_in(&root, path).unwrap();
+            assert_eq!(resolved.relative, "");
+            assert_eq!(resolved.absolute, root);
+        }
+    }
+
+    #[test]
+    fn missing_paths() {
+        let root = workspace("missing");
+        let resolved = resolve_in(&root, "new/dir/file").unwrap();
+        assert_eq!(resolved.relative, "new/dir/file");
+        assert_eq!(resolved.absolute, root.join("new/dir/file"));
+    }
+
+    #[test]
+    fn state_dir_is_off_limits() {
+        let root = workspace("state");
+        fs::create_dir_all(root.join(STATE_DIR)).unwrap();
+
+        assert!(escapes(&root, ".homeval"));
+        assert!(escapes(&root, "./.homeval/meta.json"));
+        assert!(escapes(&root, "dir/../.homeval/snapshots"));
+
+        #[cfg(unix)]
+        {
+            std::os::unix::fs::symlink(root.join(STATE_DIR), root.join("state")).unwrap();
+            assert!(escapes(&root, "state/meta.json"));
+        }
+    }
+}
@@ -1,17 +1,16 @@
 use std::{
     collections::{HashMap, VecDeque},
     pin::Pin,
-    process::{ExitStatus, Stdio},
+    process::Stdio,
     sync::{atomic::AtomicBool, Arc},
     task::{Context, Poll},
 };
 
-use crate::{ChannelMessage, IPCMessage, SendSessions};
+use crate::{processes, secrets, ChannelMessage, SendSessions};
 use anyhow::Result;
 use tokio::{
-    io::{AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf},
+    io::{AsyncWrite, AsyncWriteExt},
     process::ChildStdin,
-    sync::RwLock,
 };
 use tracing::{error, trace};
 
@@ -80,9 +79,7 @@ impl AsyncWrite for CmdWriter {
 }
 
 pub struct Proc {
-    channel: i32,
     cancelled: Arc<AtomicBool>,
-    contact: tokio::sync::mpsc::UnboundedSender<super::ChannelMessage>,
     stdin: ChildStdin,
 }
 
@@ -104,6 +101,11 @@ impl Proc {
         }
         // debug!("{:#?}", std::env::current_dir()?);
         cmd.current_dir(std::env::current_dir()?);
+
+        // Secrets first so the caller can still override them
+        cmd.envs(secrets::env().await);
+        cmd.envs(_env.unwrap_or_default());
+
         cmd.stdout(Stdio::piped());
         cmd.stderr(Stdio::piped());
         cmd.stdin(Stdio::piped());
@@ -140,6 +142,8 @@ impl Proc {
                 .expect("TODO: handle this");
         });
 
+        let process_id = processes::register(processes::Stopper::Proc(cancelled.clone())).await;
+
         let contact_clone = contact.clone();
         let cancelled_clone = cancelled.clone();
         tokio::task::spawn(async move {
@@ -164,6 +168,8 @@ impl Proc {
                 tokio::task::yield_now().await;
             }
 
+            processes::unregister(process_id).await;
+
             if contact_clone
                 .send(ChannelMessage::ProcessDead(exit_status))
                 .is_err()
@@ -172,12 +178,7 @@ impl Proc {
             }
         });
 
-        Ok(Proc {
-            channel,
-            contact,
-            cancelled,
-            stdin,
-        })
+        Ok(Proc { cancelled, stdin })
     }
 
     pub fn cancel(&mut self) {
@@ -0,0 +1,53 @@
+use portable_pty::Child;
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, LazyLock,
+    },
+};
+use tokio::sync::{Mutex, RwLock};
+use tracing::{debug, warn};
+
+// Every pty and process that is still running, so they can all be stopped when
+// the workspace goes to sleep even if the channel that started them is gone.
+static RUNNING: LazyLock<RwLock<HashMap<u64, Stopper>>> =
+    LazyLock::new(|| RwLock::new(HashMap::new()));
+static MAX_PROCESS: AtomicU64 = AtomicU64::new(0);
+
+pub(crate) enum Stopper {
+    Pty(Arc<AtomicBool>, Arc<Mutex<Box<dyn Child + Send + Sync>>>),
+    Proc(Arc<AtomicBool>),
+}
+
+pub(crate) async fn register(stopper: Stopper) -> u64 {
+    let id = MAX_PROCESS.fetch_add(1, Ordering::SeqCst);
+    RUNNING.write().await.insert(id, stopper);
+    id
+}
+
+pub(crate) async fn unregister(id: u64) {
+    RUNNING.write().await.remove(&id);
+}
+
+/// Stops every running pty and process, returns how many were stopped.
+pub async fn stop_processes() -> usize {
+    let running: Vec<(u64, Stopper)> = RUNNING.write().await.drain().collect();
+    let amount = running.len();
+
+    for (id, stopper) in running {
+        debug!(id, "Stopping process");
+        match stopper {
+            Stopper::Pty(cancelled, child) => {
+                cancelled.store(true, Ordering::SeqCst);
+                if let Err(err) = child.lock().await.kill() {
+                    warn!(%err, id, "Failed to kill pty child");
+                }
+            }
+            // Proc's reaper kills the child once it sees the cancellation
+            Stopper::Proc(cancelled) => cancelled.store(true, Ordering::SeqCst),
+        }
+    }
+
+    amount
+}
@@ -10,7 +10,7 @@ use tracing::error;
 
 use crate::ChannelMessage;
 
-use super::IPCMessage;
+use super::{processes, secrets, IPCMessage};
 
 use anyhow::{format_err, Result};
 use tokio::sync::{Mutex, RwLock};
@@ -106,10 +106,9 @@ impl Pty {
         contact: tokio::sync::mpsc::UnboundedSender<super::ChannelMessage>,
         _env: Option<HashMap<String, String>>,
     ) -> Result<Pty> {
-        let env = match _env {
-            Some(env) => env,
-            None => HashMap::new(),
-        };
+        // Secrets first so the caller can still override them
+        let mut env = secrets::env().await;
+        env.extend(_env.unwrap_or_default());
 
         let pty_system = portable_pty::native_pty_system();
 
@@ -172,9 +171,15 @@ impl Pty {
             // }
         });
 
+        let process_id = processes::register(processes::Stopper::Pty(
+            cancelled.clone(),
+            child_lock.clone(),
+        ))
+        .await;
+
         let child_lock_reaper = child_lock.clone();
         tokio::task::spawn(async move {
-            match tokio::task::spawn(async move {
+            let reaper = tokio::task::spawn(async move {
                 let mut interval = tokio::time::interval(Duration::from_millis(50));
 
                 loop {
@@ -185,9 +190,12 @@ impl Pty {
                     }
                     drop(child_);
                 }
-            })
-            .await
-            {
+            });
+
+            let result = reaper.await;
+            processes::unregister(process_id).await;
+
+            match result {
                 Ok(res) => {
                     match res {
                         Ok(exit_code) => {
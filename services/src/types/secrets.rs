@@ -0,0 +1,337 @@
+use anyhow::Result;
+use std::collections::HashMap;
+use tracing::{error, warn};
+
+#[cfg(feature = "database")]
+use anyhow::format_err;
+#[cfg(feature = "database")]
+use base64::{engine::general_purpose, Engine as _};
+#[cfg(feature = "database")]
+use orion::aead;
+#[cfg(feature = "database")]
+use std::sync::LazyLock;
+#[cfg(feature = "database")]
+use tokio::sync::OnceCell;
+#[cfg(feature = "database")]
+use tracing::info;
+
+use super::{atomic, paths};
+
+// Prefix for values encrypted at rest, followed by base64 of the sealed value
+#[cfg(feature = "database")]
+static ENCRYPTED_PREFIX: &str = "enc:v1:";
+
+// Derived from `$HOMEVAL_SECRETS_KEY`, values are stored in plain text without it
+#[cfg(feature = "database")]
+static SECRETS_KEY: LazyLock<Option<aead::SecretKey>> =
+    LazyLock::new(|| match std::env::var("HOMEVAL_SECRETS_KEY") {
+        Ok(passphrase) if !passphrase.is_empty() => {
+            let digest = orion::hash::digest(passphrase.as_bytes())
+                .expect("BLAKE2b-256 can hash any non empty input");
+            Some(
+                aead::SecretKey::from_slice(digest.as_ref())
+                    .expect("BLAKE2b-256 digests are a valid key length"),
+            )
+        }
+        _ => None,
+    });
+
+// Set once an `.env` left on disk has been moved into the secret store
+#[cfg(feature = "database")]
+static IMPORTED: OnceCell<()> = OnceCell::const_new();
+
+/// Whether `path` is the workspace's `.env` and it's kept in the secret store,
+/// so anything touching it has to go through here instead of the disk.
+pub fn is_secret(path: &paths::WorkspacePath) -> bool {
+    #[cfg(feature = "database")]
+    if super::DATABASE.get().is_some() {
+        return path.relative == ".env";
+    }
+
+    let _ = path;
+    false
+}
+
+/// Contents of `.env` as clients see it.
+pub async fn read_dotenv() -> Result<Vec<u8>> {
+    #[cfg(feature = "database")]
+    if let Some(database) = super::DATABASE.get() {
+        import(database).await?;
+        return Ok(format_dotenv(&load(database).await?).into_bytes());
+    }
+
+    // Without a database `.env` is just a regular file
+    match tokio::fs::read(paths::resolve(".env")?.absolute).await {
+        Ok(contents) => Ok(contents),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(vec![]),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Replaces every secret with the ones in `contents`.
+pub async fn write_dotenv(contents: &[u8]) -> Result<()> {
+    #[cfg(feature = "database")]
+    if let Some(database) = super::DATABASE.get() {
+        let contents =
+            std::str::from_utf8(contents).map_err(|_| format_err!(".env must be valid utf-8"))?;
+        import(database).await?;
+        return store(database, parse_dotenv(contents)).await;
+    }
+
+    atomic::write_atomic(&paths::resolve(".env")?.absolute, contents).await
+}
+
+/// Secrets to add to the environment of child processes, errors are logged
+/// so a broken secret store doesn't stop anything from running.
+pub async fn env() -> HashMap<String, String> {
+    #[cfg(feature = "database")]
+    if let Some(database) = super::DATABASE.get() {
+        if let Err(err) = import(database).await {
+            error!(%err, "Error importing .env into the secret store");
+        }
+        return match load(database).await {
+            Ok(secrets) => secrets.into_iter().collect(),
+            Err(err) => {
+                error!(%err, "Error loading secrets for child process");
+                HashMap::new()
+            }
+        };
+    }
+
+    match read_dotenv().await {
+        Ok(contents) => parse_dotenv(&String::from_utf8_lossy(&contents))
+            .into_iter()
+            .collect(),
+        Err(err) => {
+            error!(%err, "Error loading secrets for child process");
+            HashMap::new()
+        }
+    }
+}
+
+// Secrets from an `.env` that was already on disk are added to the store, the
+// ones in the store win, and the file is removed so they aren't left in plain
+// text next to it
+#[cfg(feature = "database")]
+async fn import(database: &sea_orm::DatabaseConnection) -> Result<()> {
+    IMPORTED
+        .get_or_try_init(|| async {
+            use sea_orm::{ActiveValue, EntityTrait};
+
+            let path = paths::resolve(".env")?.absolute;
+            let contents = match tokio::fs::read_to_string(&path).await {
+                Ok(contents) => contents,
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+                Err(err) => return Err(anyhow::Error::from(err)),
+            };
+
+            let existing: Vec<String> = entity::secrets::Entity::find()
+                .all(database)
+                .await?
+                .into_iter()
+                .map(|row| row.key)
+                .collect();
+
+            let mut rows = vec![];
+            for (key, value) in parse_dotenv(&contents) {
+                if !existing.contains(&key) {
+                    rows.push(entity::secrets::ActiveModel {
+                        key: ActiveValue::Set(key),
+                        value: ActiveValue::Set(encrypt(&value)?),
+                    });
+                }
+            }
+
+            let count = rows.len();
+            if !rows.is_empty() {
+                entity::secrets::Entity::insert_many(rows)
+                    .exec(database)
+                    .await?;
+            }
+
+            tokio::fs::remove_file(&path).await?;
+            info!(count, "Imported .env into the secret store");
+            Ok(())
+        })
+        .await
+        .map(|_| ())
+}
+
+#[cfg(feature = "database")]
+async fn load(database: &sea_orm::DatabaseConnection) -> Result<Vec<(String, String)>> {
+    use sea_orm::{EntityTrait, QueryOrder};
+
+    let rows = entity::secrets::Entity::find()
+        .order_by_asc(entity::secrets::Column::Key)
+        .all(database)
+        .await?;
+
+    let mut secrets = Vec::with_capacity(rows.len());
+    for row in rows {
+        match decrypt(&row.value) {
+            Ok(value) => secrets.push((row.key, value)),
+            Err(err) => warn!(%err, key = row.key, "Skipping secret that couldn't be read"),
+        }
+    }
+
+    Ok(secrets)
+}
+
+#[cfg(feature = "database")]
+async fn store(
+    database: &sea_orm::DatabaseConnection,
+    secrets: Vec<(String, String)>,
+) -> Result<()> {
+    use sea_orm::{ActiveValue, EntityTrait, TransactionTrait};
+
+    let mut rows = Vec::with_capacity(secrets.len());
+    for (key, value) in secrets {
+        rows.push(entity::secrets::ActiveModel {
+            key: ActiveValue::Set(key),
+            value: ActiveValue::Set(encrypt(&value)?),
+        });
+    }
+
+    let transaction = database.begin().await?;
+    entity::secrets::Entity::delete_many()
+        .exec(&transaction)
+        .await?;
+    if !rows.is_empty() {
+        entity::secrets::Entity::insert_many(rows)
+            .exec(&transaction)
+            .await?;
+    }
+    transaction.commit().await?;
+
+    Ok(())
+}
+
+#[cfg(feature = "database")]
+fn encrypt(value: &str) -> Result<String> {
+    match SECRETS_KEY.as_ref() {
+        Some(key) => {
+            let sealed = aead::seal(key, value.as_bytes())
+                .map_err(|_| format_err!("Couldn't encrypt secret"))?;
+            Ok(format!(
+                "{}{}",
+                ENCRYPTED_PREFIX,
+                general_purpose::STANDARD.encode(sealed)
+            ))
+        }
+        None => Ok(value.to_string()),
+    }
+}
+
+#[cfg(feature = "database")]
+fn decrypt(value: &str) -> Result<String> {
+    let sealed = match value.strip_prefix(ENCRYPTED_PREFIX) {
+        Some(sealed) => general_purpose::STANDARD.decode(sealed)?,
+        None => return Ok(value.to_string()),
+    };
+
+    let key = SECRETS_KEY
+        .as_ref()
+        .ok_or_else(|| format_err!("Secret is encrypted but $HOMEVAL_SECRETS_KEY isn't set"))?;
+    let opened = aead::open(key, &sealed)
+        .map_err(|_| format_err!("Couldn't decrypt secret, was $HOMEVAL_SECRETS_KEY changed?"))?;
+
+    Ok(String::from_utf8(opened)?)
+}
+
+fn parse_dotenv(contents: &str) -> Vec<(String, String)> {
+    let mut secrets: Vec<(String, String)> = vec![];
+
+    for line in contents.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let line = line.strip_prefix("export ").unwrap_or(line);
+        let (key, value) = match line.split_once('=') {
+            Some((key, value)) => (key.trim(), value.trim()),
+            None => {
+                warn!(line, "Ignoring .env line without a value");
+                continue;
+            }
+        };
+
+        if key.is_empty() {
+            continue;
+        }
+
+        let value = if value.len() >= 2 && value.starts_with('"') && value.ends_with('"') {
+            unescape(&value[1..value.len() - 1])
+        } else if value.len() >= 2 && value.starts_with('\'') && value.ends_with('\'') {
+            value[1..value.len() - 1].to_string()
+        } else {
+            // Unquoted values can have trailing comments
+            match value.split_once(" #") {
+                Some((value, _)) => value.trim_end().to_string(),
+                None => value.to_string(),
+            }
+        };
+
+        // Later definitions win, like when sourcing the file
+        secrets.retain(|(existing, _)| existing != key);
+        secrets.push((key.to_string(), value));
+    }
+
+    secrets
+}
+
+#[cfg(feature = "database")]
+fn format_dotenv(secrets: &[(String, String)]) -> String {
+    let mut out = String::new();
+
+    for (key, value) in secrets {
+        let needs_quotes = value.is_empty()
+            || value
+                .chars()
+                .any(|c| c.is_whitespace() || matches!(c, '"' | '\'' | '#' | '\\'));
+
+        if needs_quotes {
+            out.push_str(&format!("{}=\"{}\"\n", key, escape(value)));
+        } else {
+            out.push_str(&format!("{}={}\n", key, value));
+        }
+    }
+
+    out
+}
+
+#[cfg(feature = "database")]
+fn escape(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+fn unescape(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+
+        match chars.next() {
+            Some('n') => out.push('\n'),
+            Some('r') => out.push('\r'),
+            Some('t') => out.push('\t'),
+            Some(other) => out.push(other),
+            None => out.push('\\'),
+        }
+    }
+    out
+}
@@ -0,0 +1,72 @@
+use axum::{
+    extract::{Path, Query},
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use homeval_services::{ot_memory_usage, snapshot_store};
+use serde::Deserialize;
+use tracing::warn;
+
+use crate::auth;
+
+#[derive(Deserialize)]
+pub struct RestoreQuery {
+    // Defaults to the whole workspace
+    #[serde(default)]
+    path: String,
+}
+
+fn authorize(headers: &HeaderMap) -> Result<(), StatusCode> {
+    auth::authorize(auth::bearer(headers))
+}
+
+fn failed(err: anyhow::Error) -> Response {
+    warn!(%err, "Admin request failed");
+    (StatusCode::BAD_REQUEST, err.to_string()).into_response()
+}
+
+pub async fn list_snapshots(headers: HeaderMap) -> Response {
+    if let Err(status) = authorize(&headers) {
+        return status.into_response();
+    }
+
+    match snapshot_store::list().await {
+        Ok(snapshots) => Json(snapshots).into_response(),
+        Err(err) => failed(err),
+    }
+}
+
+pub async fn take_snapshot(headers: HeaderMap) -> Response {
+    if let Err(status) = authorize(&headers) {
+        return status.into_response();
+    }
+
+    match snapshot_store::take().await {
+        Ok(snapshot) => Json(snapshot).into_response(),
+        Err(err) => failed(err),
+    }
+}
+
+pub async fn restore_snapshot(
+    headers: HeaderMap,
+    Path(id): Path<String>,
+    Query(query): Query<RestoreQuery>,
+) -> Response {
+    if let Err(status) = authorize(&headers) {
+        return status.into_response();
+    }
+
+    match snapshot_store::restore(&id, &query.path).await {
+        Ok(()) => StatusCode::NO_CONTENT.into_response(),
+        Err(err) => failed(err),
+    }
+}
+
+pub async fn ot_usage(headers: HeaderMap) -> Response {
+    if let Err(status) = authorize(&headers) {
+        return status.into_response();
+    }
+
+    Json(ot_memory_usage()).into_response()
+}
@@ -0,0 +1,46 @@
+use axum::http::{header, HeaderMap, StatusCode};
+use std::sync::LazyLock;
+use subtle::ConstantTimeEq;
+use tracing::warn;
+
+// Shared by the admin api and the protocol inspector, which used to be set
+// with `$HOMEVAL_INSPECTOR_TOKEN`
+static TOKEN: LazyLock<Option<String>> = LazyLock::new(|| {
+    ["HOMEVAL_ADMIN_TOKEN", "HOMEVAL_INSPECTOR_TOKEN"]
+        .iter()
+        .find_map(|name| std::env::var(name).ok().filter(|token| !token.is_empty()))
+});
+
+pub fn enabled() -> bool {
+    TOKEN.is_some()
+}
+
+/// The token from an `Authorization: Bearer <token>` header
+pub fn bearer(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+}
+
+/// Checks `provided` against the admin token. Without a token configured
+/// admin routes 404, so they don't exist as far as anyone else is concerned.
+pub fn authorize(provided: Option<&str>) -> Result<(), StatusCode> {
+    let expected = TOKEN.as_ref().ok_or(StatusCode::NOT_FOUND)?;
+
+    if !token_matches(expected, provided) {
+        warn!("Rejected request with invalid admin token");
+        return Err(StatusCode::UNAUTHORIZED);
+    }
+
+    Ok(())
+}
+
+// Constant time, so how long a rejection takes says nothing about how much of
+// the token was right
+fn token_matches(expected: &str, provided: Option<&str>) -> bool {
+    match provided {
+        Some(provided) => bool::from(expected.as_bytes().ct_eq(provided.as_bytes())),
+        None => false,
+    }
+}
@@ -0,0 +1,239 @@
+use anyhow::Result;
+use base64::{engine::general_purpose, Engine as _};
+use std::{
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    sync::LazyLock,
+};
+use tokio::{fs, io::AsyncWriteExt, sync::Mutex};
+use tracing::{debug, error, info, warn};
+
+// Shared directory acting as the ownership table, every node in the cluster
+// must point `$HOMEVAL_CLUSTER_DIR` at the same place. Each claimed workspace
+// is a file containing the url of the node that owns it.
+static CLUSTER_DIR: LazyLock<Option<PathBuf>> =
+    LazyLock::new(|| match std::env::var("HOMEVAL_CLUSTER_DIR") {
+        Ok(dir) if !dir.is_empty() => Some(PathBuf::from(dir)),
+        _ => None,
+    });
+
+// Url other nodes redirect clients to for workspaces owned by this node
+static NODE_URL: LazyLock<String> = LazyLock::new(|| {
+    std::env::var("HOMEVAL_CLUSTER_URL").unwrap_or_else(|_| {
+        format!(
+            "ws://{}",
+            std::env::args()
+                .nth(1)
+                .unwrap_or_else(|| "127.0.0.1:8080".to_string())
+        )
+    })
+});
+
+// The workspace this node serves, it only has the one directory so it never
+// takes on a second one. `held` is whether it currently owns it in the table.
+#[derive(Default)]
+struct Claim {
+    workspace: Option<String>,
+    held: bool,
+}
+
+static CLAIM: LazyLock<Mutex<Claim>> = LazyLock::new(|| Mutex::new(Claim::default()));
+
+#[derive(Debug, PartialEq)]
+pub enum Route {
+    Local,
+    Redirect(String),
+    /// This node already serves a different workspace nobody else owns
+    Refuse,
+}
+
+pub fn enabled() -> bool {
+    CLUSTER_DIR.is_some()
+}
+
+fn entry_path(dir: &Path, workspace: &str) -> PathBuf {
+    // Workspace ids come from the token, so keep them from escaping the table
+    dir.join(general_purpose::URL_SAFE_NO_PAD.encode(workspace))
+}
+
+/// Works out which node should serve `workspace`, claiming it for this node
+/// if nobody owns it yet.
+pub async fn route(workspace: &str, token: &str) -> Result<Route> {
+    let dir = match CLUSTER_DIR.as_ref() {
+        Some(dir) => dir,
+        None => return Ok(Route::Local),
+    };
+
+    route_in(dir, &NODE_URL, &mut *CLAIM.lock().await, workspace, token).await
+}
+
+async fn route_in(
+    dir: &Path,
+    node_url: &str,
+    claim: &mut Claim,
+    workspace: &str,
+    token: &str,
+) -> Result<Route> {
+    fs::create_dir_all(dir).await?;
+    let path = entry_path(dir, workspace);
+
+    if let Some(serving) = claim.workspace.as_deref().filter(|id| *id != workspace) {
+        return match owner(&path).await? {
+            Some(owner) if owner != node_url => Ok(redirect(workspace, &owner, token)),
+            _ => {
+                warn!(workspace, serving, "Refusing a second workspace");
+                Ok(Route::Refuse)
+            }
+        };
+    }
+
+    if claim.held {
+        return Ok(Route::Local);
+    }
+
+    match fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&path)
+        .await
+    {
+        Ok(mut file) => {
+            file.write_all(node_url.as_bytes()).await?;
+            file.sync_all().await?;
+            info!(workspace, "Claimed workspace");
+        }
+        Err(err) if err.kind() == ErrorKind::AlreadyExists => {
+            let owner = owner(&path).await?.unwrap_or_default();
+
+            // Left over from before a restart of this node
+            if owner != node_url {
+                return Ok(redirect(workspace, &owner, token));
+            }
+        }
+        Err(err) => return Err(err.into()),
+    }
+
+    claim.workspace = Some(workspace.to_string());
+    claim.held = true;
+    Ok(Route::Local)
+}
+
+async fn owner(path: &Path) -> Result<Option<String>> {
+    match fs::read_to_string(path).await {
+        Ok(owner) => Ok(Some(owner.trim().to_string())),
+        Err(err) if err.kind() == ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err.into()),
+    }
+}
+
+fn redirect(workspace: &str, owner: &str, token: &str) -> Route {
+    debug!(workspace, owner, "Workspace owned by another node");
+    Route::Redirect(format!("{}/wsv2/{}", owner.trim_end_matches('/'), token))
+}
+
+/// Gives up ownership of the workspace claimed by this node.
+pub async fn release() {
+    let dir = match CLUSTER_DIR.as_ref() {
+        Some(dir) => dir,
+        None => return,
+    };
+
+    let mut claim = CLAIM.lock().await;
+    let workspace = match claim.workspace.as_deref().filter(|_| claim.held) {
+        Some(workspace) => workspace.to_string(),
+        None => return,
+    };
+    claim.held = false;
+
+    let path = entry_path(dir, &workspace);
+
+    // Only remove the entry if it still points at us
+    match fs::read_to_string(&path).await {
+        Ok(owner) if owner.trim() == NODE_URL.as_str() => {
+            if let Err(err) = fs::remove_file(&path).await {
+                error!(%err, workspace, "Error releasing workspace");
+                return;
+            }
+            info!(workspace, "Released workspace");
+        }
+        Ok(owner) => warn!(workspace, owner, "Workspace was claimed by another node"),
+        Err(err) => warn!(%err, workspace, "Workspace entry missing while releasing"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NODE: &str = "ws://node-a";
+
+    fn table(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("homeval-cluster-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[tokio::test]
+    async fn claims_unowned_workspace() {
+        let dir = table("claim");
+        let mut claim = Claim::default();
+
+        let route = route_in(&dir, NODE, &mut claim, "repl", "token").await;
+        assert_eq!(route.unwrap(), Route::Local);
+        assert_eq!(
+            std::fs::read_to_string(entry_path(&dir, "repl")).unwrap(),
+            NODE
+        );
+        assert_eq!(claim.workspace.as_deref(), Some("repl"));
+    }
+
+    #[tokio::test]
+    async fn redirects_to_owner() {
+        let dir = table("redirect");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(entry_path(&dir, "repl"), "ws://node-b/").unwrap();
+        let mut claim = Claim::default();
+
+        let route = route_in(&dir, NODE, &mut claim, "repl", "token").await;
+        assert_eq!(
+            route.unwrap(),
+            Route::Redirect("ws://node-b/wsv2/token".to_string())
+        );
+        assert!(claim.workspace.is_none());
+    }
+
+    #[tokio::test]
+    async fn refuses_second_workspace() {
+        let dir = table("second");
+        let mut claim = Claim::default();
+        route_in(&dir, NODE, &mut claim, "repl", "token")
+            .await
+            .unwrap();
+
+        let route = route_in(&dir, NODE, &mut claim, "other", "token").await;
+        assert_eq!(route.unwrap(), Route::Refuse);
+        assert!(!entry_path(&dir, "other").exists());
+
+        // Still refused after letting go of the first one
+        claim.held = false;
+        let route = route_in(&dir, NODE, &mut claim, "other", "token").await;
+        assert_eq!(route.unwrap(), Route::Refuse);
+    }
+
+    #[tokio::test]
+    async fn redirects_second_workspace_to_its_owner() {
+        let dir = table("second-owned");
+        let mut claim = Claim::default();
+        route_in(&dir, NODE, &mut claim, "repl", "token")
+            .await
+            .unwrap();
+        std::fs::write(entry_path(&dir, "other"), "ws://node-b").unwrap();
+
+        let route = route_in(&dir, NODE, &mut claim, "other", "token").await;
+        assert_eq!(
+            route.unwrap(),
+            Route::Redirect("ws://node-b/wsv2/token".to_string())
+        );
+    }
+}
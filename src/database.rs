@@ -2,10 +2,9 @@ use anyhow::Result;
 use migration::MigratorTrait;
 use sea_orm::{ConnectOptions, Database};
 use std::time::Duration;
-use tokio::sync::OnceCell;
 use tracing::{debug, warn};
 
-pub static DATABASE: OnceCell<sea_orm::DatabaseConnection> = OnceCell::const_new();
+pub use homeval_services::DATABASE;
 
 // TODO: allow disabling of db at runtime as well as compile time
 pub async fn setup() -> Result<()> {
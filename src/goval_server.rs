@@ -4,7 +4,7 @@ use axum::{
         ConnectInfo, Path, State,
     },
     response::{IntoResponse, Response},
-    routing::get,
+    routing::{get, post},
     Router,
 };
 
@@ -13,7 +13,7 @@ use chrono::Datelike;
 
 use anyhow::Result;
 use goval::{Command, OpenChannel};
-use homeval_services::{ClientInfo, ServiceMetadata};
+use homeval_services::{AttachRule, ClientInfo, ServiceMetadata};
 use prost::Message;
 use std::{net::SocketAddr, sync::LazyLock};
 use tokio::sync::{mpsc::UnboundedSender, Mutex};
@@ -27,7 +27,7 @@ use crate::{
     PROCCESS_CHANNEL_TO_ID, SESSION_CHANNELS, SESSION_CLIENT_INFO, SESSION_MAP,
 };
 
-use crate::{parse_paseto::parse, ChannelMessage, IPCMessage};
+use crate::{admin, cluster, lifecycle, parse_paseto::parse, ChannelMessage, IPCMessage};
 
 #[derive(Clone)]
 struct AppState {
@@ -46,6 +46,26 @@ pub async fn start_server() -> Result<()> {
 
     let app = Router::new()
         .route("/wsv2/:token", get(wsv2))
+        .route(
+            "/snapshots",
+            get(admin::list_snapshots).post(admin::take_snapshot),
+        )
+        .route("/snapshots/:id/restore", post(admin::restore_snapshot))
+        .route("/ot/usage", get(admin::ot_usage));
+
+    if crate::auth::enabled() {
+        info!("Admin api enabled at /snapshots and /ot/usage");
+    }
+
+    #[cfg(feature = "inspector")]
+    let app = {
+        if crate::auth::enabled() {
+            info!("Protocol inspector enabled at /inspect");
+        }
+        app.route("/inspect", get(crate::inspector::inspect))
+    };
+
+    let app = app
         .fallback(get(default_handler))
         .with_state(AppState { sender: tx });
     info!("Goval server listening on: {}", addr);
@@ -72,7 +92,56 @@ async fn default_handler() -> Response {
     DEFAULT_REPLY.into_response()
 }
 
-async fn on_wsv2_upgrade(socket: WebSocket, token: String, state: AppState, addr: SocketAddr) {
+async fn on_wsv2_upgrade(mut socket: WebSocket, token: String, state: AppState, addr: SocketAddr) {
+    let (client, workspace) = match parse(&token).await {
+        // Unverified tokens could claim or redirect any workspace
+        Ok(parsed) => (parsed.client, parsed.workspace.filter(|_| parsed.is_secure)),
+        Err(_) => (ClientInfo::default(), None),
+    };
+
+    if let Some(workspace) = workspace.filter(|_| cluster::enabled()) {
+        match cluster::route(&workspace, &token).await {
+            Ok(cluster::Route::Local) => {}
+            Ok(cluster::Route::Redirect(url)) => {
+                info!(peer_address = %addr, workspace, url, "Redirecting to owning node");
+                let redirect = goval::Command {
+                    body: Some(goval::command::Body::Redirect(goval::Redirect { url })),
+                    ..Default::default()
+                };
+
+                if let Err(err) = socket
+                    .send(WsMessage::Binary(redirect.encode_to_vec()))
+                    .await
+                {
+                    error!(?err, "Error sending redirect");
+                }
+                let _ = socket.close().await;
+                return;
+            }
+            Ok(cluster::Route::Refuse) => {
+                let refusal = goval::Command {
+                    body: Some(goval::command::Body::Error(format!(
+                        "{}: this node serves another workspace",
+                        workspace
+                    ))),
+                    ..Default::default()
+                };
+
+                if let Err(err) = socket
+                    .send(WsMessage::Binary(refusal.encode_to_vec()))
+                    .await
+                {
+                    error!(?err, "Error sending refusal");
+                }
+                let _ = socket.close().await;
+                return;
+            }
+            Err(err) => {
+                error!(%err, workspace, "Error looking up workspace owner, serving locally")
+            }
+        }
+    }
+
     debug!("Waiting for mutex...");
     let mut max_session = MAX_SESSION.lock().await;
 
@@ -88,21 +157,15 @@ async fn on_wsv2_upgrade(socket: WebSocket, token: String, state: AppState, addr
         .insert(session_id, send_to_session);
 
     let tx_clone = state.sender.clone();
-    match accept_connection(
-        socket,
-        tx_clone,
-        session_recv,
-        session_id,
-        parse(&token).await.unwrap_or(ClientInfo::default()),
-        addr,
-    )
-    .await
-    {
+    match accept_connection(socket, tx_clone, session_recv, session_id, client, addr).await {
         Ok(_) => {}
         Err(err) => {
             error!(?err, "accept_connection errored")
         }
     };
+
+    // Does nothing if the session was already closed
+    close_session(session_id).await;
 }
 
 async fn wsv2(
@@ -158,6 +221,26 @@ async fn handle_message(
                 }
             }
 
+            // There's no service on the control channel to have capabilities,
+            // services answer for themselves on their own channels
+            goval::command::Body::HasCap(_) => reply_has_cap(&message, false, session_map).await,
+            goval::command::Body::AlwaysOn(always_on) => {
+                let sessions = session_map.read().await.len();
+                lifecycle::set_always_on(always_on.enable, sessions).await;
+
+                let ok = goval::Command {
+                    body: Some(goval::command::Body::Ok(goval::Ok {})),
+                    r#ref: cmd.r#ref,
+                    channel: 0,
+                    ..Default::default()
+                };
+
+                if let Some(sender) = session_map.read().await.get(&message.session) {
+                    if let Err(err) = sender.send(message.replace_cmd(ok)) {
+                        error!(?err, "Error occured while replying to AlwaysOn");
+                    }
+                }
+            }
             goval::command::Body::CloseChan(close_chan) => {
                 // TODO: follow close_chan.action
                 tokio::spawn(async move {
@@ -173,6 +256,17 @@ async fn handle_message(
             _ => {}
         }
     } else {
+        if let goval::command::Body::HasCap(_) = cmd_body {
+            let has_cap = match CHANNEL_METADATA.read().await.get(&cmd.channel) {
+                Some(metadata) => homeval_services::service(&metadata.service)
+                    .is_some_and(|service| !service.is_stub()),
+                None => false,
+            };
+
+            reply_has_cap(&message, has_cap, session_map).await;
+            return;
+        }
+
         // Directly deal with Command::Input, should be faster
         if let goval::command::Body::Input(input) = cmd_body {
             if let Some(pty_id) = PROCCESS_CHANNEL_TO_ID.read().await.get(&cmd.channel) {
@@ -208,6 +302,29 @@ async fn handle_message(
     }
 }
 
+async fn reply_has_cap(
+    message: &IPCMessage,
+    has_cap: bool,
+    session_map: &LazyLock<
+        tokio::sync::RwLock<std::collections::HashMap<i32, mpsc::UnboundedSender<IPCMessage>>>,
+    >,
+) {
+    let reply = goval::Command {
+        body: Some(goval::command::Body::HasCap(has_cap)),
+        r#ref: message.command.r#ref.clone(),
+        channel: message.command.channel,
+        ..Default::default()
+    };
+
+    if let Some(sender) = session_map.read().await.get(&message.session) {
+        if let Err(err) = sender.send(message.replace_cmd(reply)) {
+            error!(?err, "Error occured while replying to hasCap");
+        }
+    } else {
+        error!("Missing session queue when replying to hasCap")
+    }
+}
+
 async fn open_channel(
     open_chan: OpenChannel,
     message: IPCMessage,
@@ -216,24 +333,22 @@ async fn open_channel(
         tokio::sync::RwLock<std::collections::HashMap<i32, mpsc::UnboundedSender<IPCMessage>>>,
     >,
 ) -> Result<()> {
-    let searcher: &str = &open_chan.service;
-    if homeval_services::IMPLEMENTED_SERVICES.contains(&searcher) {
+    if let Some(service_info) = homeval_services::service(&open_chan.service) {
         let mut found = false;
         let mut channel_id_held = 0;
 
+        let singleton = service_info.attach == AttachRule::Singleton;
         let attach = open_chan.action() == goval::open_channel::Action::AttachOrCreate
             || open_chan.action() == goval::open_channel::Action::Attach
-            || open_chan.service == "git"; // git is just use for replspace api stuff
-                                           // from what I can tell, so its just easier to have it as one instance.
+            || singleton;
         let create = open_chan.action() == goval::open_channel::Action::AttachOrCreate
             || open_chan.action() == goval::open_channel::Action::Create;
         if attach {
             let metadata = CHANNEL_METADATA.read().await;
             for (id, channel) in metadata.iter() {
-                if channel.name.is_some()
-                    && channel.name.clone().unwrap_or("".to_string()) == open_chan.name
-                    && channel.service.clone() == open_chan.service
-                {
+                let same_name = channel.name.is_some()
+                    && channel.name.clone().unwrap_or("".to_string()) == open_chan.name;
+                if (singleton || same_name) && channel.service.clone() == open_chan.service {
                     found = true;
                     channel_id_held = *id;
                     continue;
@@ -428,6 +543,38 @@ async fn detach_channel(channel: i32, session: i32, forced: bool) -> Result<()>
     Ok(())
 }
 
+async fn close_session(session: i32) {
+    let channels = SESSION_CHANNELS.write().await.remove(&session);
+
+    // Sessions that fail before their channels are set up still have to be
+    // removed, or the workspace never goes idle
+    let mut session_map = SESSION_MAP.write().await;
+    let connected = session_map.remove(&session).is_some();
+    let remaining = session_map.len();
+    drop(session_map);
+
+    if channels.is_none() && !connected {
+        return;
+    }
+
+    warn!(session, "CLOSING SESSION");
+    for channel in channels.unwrap_or_default() {
+        tokio::spawn(async move {
+            match detach_channel(channel, session, true).await {
+                Ok(_) => {}
+                Err(err) => {
+                    error!(%err, session, channel, "Error occured while detaching from channel")
+                }
+            }
+        });
+    }
+
+    SESSION_CLIENT_INFO.write().await.remove(&session);
+    warn!(session, "CLOSED SESSION");
+
+    lifecycle::session_ended(remaining).await;
+}
+
 async fn send_message(
     message: goval::Command,
     stream: &mut futures_util::stream::SplitSink<WebSocket, WsMessage>,
@@ -457,19 +604,31 @@ async fn accept_connection(
 
     let (mut write, mut read) = ws_stream.split();
 
-    let mut boot_status = goval::Command::default();
-    let inner = goval::BootStatus {
-        stage: goval::boot_status::Stage::Complete.into(),
-        ..Default::default()
-    };
-    boot_status.body = Some(goval::command::Body::BootStatus(inner));
+    lifecycle::session_started().await;
+
+    // Stream boot status until the workspace has finished booting (or waking up)
+    let mut boot_reader = lifecycle::BOOT_STATUS.subscribe();
+    loop {
+        let inner = boot_reader.borrow_and_update().clone();
+        let complete = inner.stage() == goval::boot_status::Stage::Complete;
+
+        let boot_status = goval::Command {
+            body: Some(goval::command::Body::BootStatus(inner)),
+            ..Default::default()
+        };
+        send_message(boot_status, &mut write).await?;
 
-    send_message(boot_status, &mut write).await?;
+        if complete {
+            break;
+        }
+
+        boot_reader.changed().await?;
+    }
 
     // Sending container state
     let mut container_state = goval::Command::default();
     let inner_state = goval::ContainerState {
-        state: goval::container_state::State::Ready.into(),
+        state: (*lifecycle::CONTAINER_STATE.borrow()).into(),
     };
     container_state.body = Some(goval::command::Body::ContainerState(inner_state));
 
@@ -489,8 +648,9 @@ async fn accept_connection(
     #[cfg(feature = "fun-stuff")]
     if date.month() == 6 && date.day() == 10 {
         let mut toast = goval::Command::default();
-        let mut inner_state = goval::Toast::default();
-        inner_state.text = "Say happy birthday to @haroon!".to_string();
+        let inner_state = goval::Toast {
+            text: "Say happy birthday to @haroon!".to_string(),
+        };
         toast.body = Some(goval::command::Body::Toast(inner_state));
 
         send_message(toast, &mut write).await?;
@@ -515,30 +675,15 @@ async fn accept_connection(
                                 }
                             };
 
+                            #[cfg(feature = "inspector")]
+                            crate::inspector::tap(crate::inspector::Direction::In, &message);
+
                             if let Err(err) = propagate.send(message) {
                                 error!(session = session, ?err, "An error occured when enqueing message to global message queue")
                             }
                         }
                         WsMessage::Close(_) => {
-                            warn!(session, "CLOSING SESSION");
-                            for _channel in
-                                SESSION_CHANNELS.read().await.get(&session).unwrap().iter()
-                            {
-                                let channel = *_channel;
-                                tokio::spawn(async move {
-                                    match detach_channel(channel, session, true).await {
-                                        Ok(_) => {}
-                                        Err(err) => {
-                                            error!(%err, session, channel, "Error occured while detaching from channel")
-                                        }
-                                    }
-                                });
-                            }
-
-                            SESSION_MAP.write().await.remove(&session);
-                            SESSION_CLIENT_INFO.write().await.remove(&session);
-                            SESSION_CHANNELS.write().await.remove(&session);
-                            warn!(session, "CLOSED SESSION");
+                            close_session(session).await;
                         }
                         _ => {}
                     }
@@ -552,9 +697,15 @@ async fn accept_connection(
                 }
             };
         }
+
+        // Connections can drop without a close frame, make sure the session is cleaned up
+        close_session(session).await;
     });
 
     while let Some(i) = sent.recv().await {
+        #[cfg(feature = "inspector")]
+        crate::inspector::tap(crate::inspector::Direction::Out, &i);
+
         match write.send(WsMessage::Binary(i.to_bytes())).await {
             Ok(_) => {}
             Err(err) => {
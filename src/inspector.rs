@@ -0,0 +1,237 @@
+use axum::{
+    extract::{
+        ws::{Message as WsMessage, WebSocket, WebSocketUpgrade},
+        Query,
+    },
+    http::HeaderMap,
+    response::{IntoResponse, Response},
+};
+use homeval_services::IPCMessage;
+use prost::Message;
+use prost_reflect::{DescriptorPool, DynamicMessage, MessageDescriptor, SerializeOptions};
+use serde::{Deserialize, Serialize};
+use std::{
+    sync::LazyLock,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tokio::sync::broadcast::{self, error::RecvError};
+use tracing::{error, info};
+
+use crate::{auth, CHANNEL_METADATA};
+
+static COMMAND_DESCRIPTOR: LazyLock<MessageDescriptor> = LazyLock::new(|| {
+    DescriptorPool::decode(goval::FILE_DESCRIPTOR_SET)
+        .expect("goval.proto descriptor set is generated at build time")
+        .get_message_by_name("goval.Command")
+        .expect("goval.proto defines goval.Command")
+});
+
+// Every inbound and outbound command is published here while at least one
+// inspector is connected, so the tap is free when nobody is listening.
+static TAP: LazyLock<broadcast::Sender<Inspected>> = LazyLock::new(|| broadcast::channel(1024).0);
+
+#[derive(Serialize, Clone, Copy, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    In,
+    Out,
+}
+
+#[derive(Clone, Debug)]
+struct Inspected {
+    direction: Direction,
+    session: i32,
+    timestamp: u128,
+    command: goval::Command,
+}
+
+#[derive(Serialize)]
+struct InspectedJson<'a> {
+    direction: Direction,
+    session: i32,
+    channel: i32,
+    service: &'a str,
+    body: &'a str,
+    timestamp: u128,
+    command: serde_json::Value,
+}
+
+#[derive(Deserialize)]
+pub struct InspectQuery {
+    token: Option<String>,
+    session: Option<i32>,
+    channel: Option<i32>,
+    /// Comma separated list of services to include
+    service: Option<String>,
+    /// Comma separated list of command body types (as named in goval.proto) to include
+    body: Option<String>,
+}
+
+struct Filter {
+    session: Option<i32>,
+    channel: Option<i32>,
+    services: Option<Vec<String>>,
+    bodies: Option<Vec<String>>,
+}
+
+impl From<InspectQuery> for Filter {
+    fn from(query: InspectQuery) -> Self {
+        let split = |list: String| -> Vec<String> {
+            list.split(',')
+                .map(|item| item.trim().to_string())
+                .filter(|item| !item.is_empty())
+                .collect()
+        };
+
+        Filter {
+            session: query.session,
+            channel: query.channel,
+            services: query.service.map(split),
+            bodies: query.body.map(split),
+        }
+    }
+}
+
+pub fn tap(direction: Direction, message: &IPCMessage) {
+    if TAP.receiver_count() == 0 {
+        return;
+    }
+
+    let inspected = Inspected {
+        direction,
+        session: message.session,
+        timestamp: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis(),
+        command: message.command.clone(),
+    };
+
+    // Only fails if every inspector disconnected since the receiver count check
+    let _ = TAP.send(inspected);
+}
+
+pub async fn inspect(
+    ws: WebSocketUpgrade,
+    headers: HeaderMap,
+    Query(query): Query<InspectQuery>,
+) -> Response {
+    let provided = query.token.as_deref().or_else(|| auth::bearer(&headers));
+    if let Err(status) = auth::authorize(provided) {
+        return status.into_response();
+    }
+
+    ws.on_upgrade(move |socket| stream_commands(socket, query.into()))
+}
+
+async fn stream_commands(mut socket: WebSocket, filter: Filter) {
+    info!(
+        session = filter.session,
+        channel = filter.channel,
+        "Inspector attached"
+    );
+
+    let mut reader = TAP.subscribe();
+    loop {
+        tokio::select! {
+            inspected = reader.recv() => {
+                let text = match inspected {
+                    Ok(inspected) => match encode(&inspected, &filter).await {
+                        Some(text) => text,
+                        None => continue,
+                    },
+                    Err(RecvError::Lagged(amount)) => {
+                        serde_json::json!({ "lagged": amount }).to_string()
+                    }
+                    Err(RecvError::Closed) => break,
+                };
+
+                if socket.send(WsMessage::Text(text)).await.is_err() {
+                    break;
+                }
+            }
+            incoming = socket.recv() => match incoming {
+                Some(Ok(WsMessage::Close(_))) | Some(Err(_)) | None => break,
+                Some(Ok(_)) => {}
+            }
+        }
+    }
+
+    info!("Inspector detached");
+}
+
+async fn encode(inspected: &Inspected, filter: &Filter) -> Option<String> {
+    let command = &inspected.command;
+
+    if filter
+        .session
+        .is_some_and(|session| session != inspected.session)
+        || filter
+            .channel
+            .is_some_and(|channel| channel != command.channel)
+    {
+        return None;
+    }
+
+    let service = if command.channel == 0 {
+        String::new()
+    } else {
+        CHANNEL_METADATA
+            .read()
+            .await
+            .get(&command.channel)
+            .map(|metadata| metadata.service.clone())
+            .unwrap_or_default()
+    };
+
+    if let Some(services) = &filter.services {
+        if !services.contains(&service) {
+            return None;
+        }
+    }
+
+    let dynamic = match DynamicMessage::decode(
+        COMMAND_DESCRIPTOR.clone(),
+        command.encode_to_vec().as_slice(),
+    ) {
+        Ok(dynamic) => dynamic,
+        Err(err) => {
+            error!(%err, "Error decoding command for inspector");
+            return None;
+        }
+    };
+
+    let body = COMMAND_DESCRIPTOR
+        .oneofs()
+        .find(|oneof| oneof.name() == "body")
+        .and_then(|oneof| oneof.fields().find(|field| dynamic.has_field(field)))
+        .map(|field| field.name().to_string())
+        .unwrap_or_default();
+
+    if let Some(bodies) = &filter.bodies {
+        if !bodies.contains(&body) {
+            return None;
+        }
+    }
+
+    let options = SerializeOptions::new().skip_default_fields(true);
+    let json = match dynamic.serialize_with_options(serde_json::value::Serializer, &options) {
+        Ok(json) => json,
+        Err(err) => {
+            error!(%err, "Error serializing command for inspector");
+            return None;
+        }
+    };
+
+    let inspected = InspectedJson {
+        direction: inspected.direction,
+        session: inspected.session,
+        channel: command.channel,
+        service: &service,
+        body: &body,
+        timestamp: inspected.timestamp,
+        command: json,
+    };
+
+    serde_json::to_string(&inspected).ok()
+}
@@ -0,0 +1,186 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        LazyLock,
+    },
+    time::Duration,
+};
+
+use goval::{boot_status::Stage, container_state::State};
+use homeval_services::ChannelMessage;
+use tokio::{sync::watch, sync::Mutex, task::JoinHandle};
+use tracing::{debug, error, info};
+
+use crate::{
+    cluster, CHANNEL_MESSAGES, CHANNEL_METADATA, CHANNEL_SESSIONS, DOTREPLIT_CONFIG,
+    LAST_SESSION_USING_CHANNEL, PROCCESS_CHANNEL_TO_ID, SESSION_MAP,
+};
+
+static DEFAULT_IDLE_TIMEOUT: u64 = 300;
+
+// How long the workspace stays awake after the last session leaves,
+// `None` if `$HOMEVAL_IDLE_TIMEOUT` is set to 0 to disable sleeping.
+static IDLE_TIMEOUT: LazyLock<Option<Duration>> = LazyLock::new(|| {
+    let secs = std::env::var("HOMEVAL_IDLE_TIMEOUT")
+        .ok()
+        .and_then(|secs| secs.parse().ok())
+        .unwrap_or(DEFAULT_IDLE_TIMEOUT);
+
+    if secs == 0 {
+        None
+    } else {
+        Some(Duration::from_secs(secs))
+    }
+});
+
+pub static BOOT_STATUS: LazyLock<watch::Sender<goval::BootStatus>> = LazyLock::new(|| {
+    watch::channel(goval::BootStatus {
+        stage: Stage::Handshake.into(),
+        ..Default::default()
+    })
+    .0
+});
+
+pub static CONTAINER_STATE: LazyLock<watch::Sender<State>> =
+    LazyLock::new(|| watch::channel(State::Sleep).0);
+
+static BOOTED: AtomicBool = AtomicBool::new(false);
+static ALWAYS_ON: AtomicBool = AtomicBool::new(false);
+static IDLE_TIMER: Mutex<Option<JoinHandle<()>>> = Mutex::const_new(None);
+// Held while going to sleep or waking up so the two can't interleave
+static TRANSITION: Mutex<()> = Mutex::const_new(());
+
+pub fn set_boot_stage(stage: Stage, progress: u32, total: u32) {
+    debug!(?stage, progress, total, "Boot stage changed");
+    BOOT_STATUS.send_replace(goval::BootStatus {
+        stage: stage.into(),
+        progress,
+        total,
+    });
+}
+
+pub fn boot_complete() {
+    set_boot_stage(Stage::Complete, 0, 0);
+    CONTAINER_STATE.send_replace(State::Ready);
+    BOOTED.store(true, Ordering::SeqCst);
+    info!("Workspace is ready");
+}
+
+/// Called when a new session connects, cancels any pending sleep and cold
+/// starts the workspace if it had already gone to sleep.
+pub async fn session_started() {
+    if let Some(timer) = IDLE_TIMER.lock().await.take() {
+        debug!("Session connected, cancelling idle timer");
+        timer.abort();
+    }
+
+    let _transition = TRANSITION.lock().await;
+    let sleeping = BOOTED.load(Ordering::SeqCst)
+        && *CONTAINER_STATE.borrow() == State::Sleep
+        && BOOT_STATUS.borrow().stage() == Stage::Handshake;
+    if sleeping {
+        // Set before spawning so sessions connecting meanwhile don't wake twice
+        set_boot_stage(Stage::Acquiring, 0, 2);
+        tokio::spawn(wake());
+    }
+}
+
+/// Called after a session disconnects with the amount of sessions left.
+pub async fn session_ended(remaining: usize) {
+    if remaining == 0 {
+        start_idle_timer().await;
+    }
+}
+
+pub async fn set_always_on(enable: bool, sessions: usize) {
+    info!(enable, "Always on changed");
+    ALWAYS_ON.store(enable, Ordering::SeqCst);
+
+    if enable {
+        if let Some(timer) = IDLE_TIMER.lock().await.take() {
+            timer.abort();
+        }
+    } else if sessions == 0 {
+        start_idle_timer().await;
+    }
+}
+
+async fn start_idle_timer() {
+    let timeout = match *IDLE_TIMEOUT {
+        Some(timeout) => timeout,
+        None => return,
+    };
+
+    if ALWAYS_ON.load(Ordering::SeqCst) {
+        debug!("Always on is enabled, not starting idle timer");
+        return;
+    }
+
+    debug!(?timeout, "Last session left, starting idle timer");
+    let timer = tokio::spawn(async move {
+        tokio::time::sleep(timeout).await;
+        IDLE_TIMER.lock().await.take();
+        sleep().await;
+    });
+
+    if let Some(previous) = IDLE_TIMER.lock().await.replace(timer) {
+        previous.abort();
+    }
+}
+
+async fn sleep() {
+    let _transition = TRANSITION.lock().await;
+
+    // The timer can't be cancelled anymore once it's waiting for the lock, a
+    // session connecting meanwhile has to be caught here instead
+    if !SESSION_MAP.read().await.is_empty() || ALWAYS_ON.load(Ordering::SeqCst) {
+        debug!("Workspace is in use again, not going to sleep");
+        return;
+    }
+    if *CONTAINER_STATE.borrow() != State::Ready {
+        debug!("Workspace isn't awake, not going to sleep");
+        return;
+    }
+
+    info!("Workspace idle, going to sleep");
+
+    // Channels normally shut down when their last session leaves, anything
+    // still around at this point was left behind so flush it now.
+    for queue in CHANNEL_MESSAGES.read().await.values() {
+        if let Err(err) = queue.send(ChannelMessage::Shutdown) {
+            error!(%err, "Error shutting down channel before sleeping");
+        }
+    }
+
+    CHANNEL_METADATA.write().await.clear();
+    CHANNEL_SESSIONS.write().await.clear();
+    CHANNEL_MESSAGES.write().await.clear();
+    PROCCESS_CHANNEL_TO_ID.write().await.clear();
+    LAST_SESSION_USING_CHANNEL.write().await.clear();
+
+    let stopped = homeval_services::stop_processes().await;
+    debug!(stopped, "Stopped running processes");
+
+    // Let other nodes pick the workspace up while it's asleep
+    cluster::release().await;
+
+    set_boot_stage(Stage::Handshake, 0, 0);
+    CONTAINER_STATE.send_replace(State::Sleep);
+}
+
+async fn wake() {
+    let _transition = TRANSITION.lock().await;
+    info!("Waking workspace");
+    set_boot_stage(Stage::Acquiring, 1, 2);
+
+    match std::fs::read_to_string(".replit") {
+        Ok(config) => match toml::from_str(&config) {
+            Ok(dotreplit) => *DOTREPLIT_CONFIG.write().await = dotreplit,
+            Err(err) => error!(%err, "Error parsing .replit while waking"),
+        },
+        Err(err) => debug!(%err, "Couldn't read .replit while waking"),
+    }
+
+    set_boot_stage(Stage::Acquiring, 2, 2);
+    boot_complete();
+}
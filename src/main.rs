@@ -1,7 +1,6 @@
 #![feature(lazy_cell)]
 
 use std::sync::LazyLock;
-use std::time::Instant;
 use std::{collections::HashMap, io::Error, sync::Arc};
 
 use tokio::sync::{mpsc, Mutex, RwLock};
@@ -25,12 +24,13 @@ mod replspace_server;
 #[cfg(feature = "repldb")]
 mod repldb_server;
 
-pub static START_TIME: LazyLock<Instant> = LazyLock::new(Instant::now);
+#[cfg(feature = "inspector")]
+mod inspector;
+
+pub use homeval_services::START_TIME;
 static CPU_STATS: LazyLock<Arc<cpu_time::ProcessTime>> =
     LazyLock::new(|| Arc::new(cpu_time::ProcessTime::now()));
 
-pub static IMPLEMENTED_SERVICES: LazyLock<Vec<String>> = LazyLock::new(Vec::new);
-
 pub static DOTREPLIT_CONFIG: LazyLock<Arc<RwLock<DotReplit>>> = LazyLock::new(|| {
     Arc::new(RwLock::const_new(
         toml::from_str(&std::fs::read_to_string(".replit").unwrap_or("".to_string())).unwrap(),
@@ -76,7 +76,11 @@ mod database;
 #[cfg(feature = "database")]
 pub use database::DATABASE;
 
+mod admin;
+mod auth;
+mod cluster;
 mod goval_server;
+mod lifecycle;
 
 #[tokio::main]
 async fn main() -> Result<(), Error> {
@@ -91,10 +95,19 @@ async fn main() -> Result<(), Error> {
 
     // console_subscriber::init();
 
+    info!("Starting homeval!");
+
+    // Start accepting connections straight away, sessions are sent boot status
+    // updates until the rest of the setup is done.
+    let goval_server = tokio::spawn(goval_server::start_server());
+
     #[cfg(feature = "database")]
-    database::setup().await.unwrap();
+    {
+        lifecycle::set_boot_stage(goval::boot_status::Stage::BlockMigration, 1, 2);
+        database::setup().await.unwrap();
+    }
 
-    info!("Starting homeval!");
+    lifecycle::set_boot_stage(goval::boot_status::Stage::Acquiring, 2, 2);
 
     #[cfg(feature = "replspace")]
     tokio::spawn(replspace_server::start_server());
@@ -102,7 +115,9 @@ async fn main() -> Result<(), Error> {
     #[cfg(feature = "repldb")]
     tokio::spawn(repldb_server::start_server());
 
-    goval_server::start_server().await.unwrap();
+    lifecycle::boot_complete();
+
+    goval_server.await?.unwrap();
 
     Ok(())
 }
@@ -124,7 +124,15 @@ async fn parse_verify(input: &str) -> Result<(Vec<u8>, bool)> {
     Ok((result.payload().as_bytes().to_vec(), true))
 }
 
-pub async fn parse(token: &str) -> Result<ClientInfo> {
+pub struct ParsedToken {
+    pub client: ClientInfo,
+    /// Id of the repl the token grants access to, if it names one
+    pub workspace: Option<String>,
+    /// Whether the token's signature was checked
+    pub is_secure: bool,
+}
+
+pub async fn parse(token: &str) -> Result<ParsedToken> {
     let msg;
     let is_secure;
 
@@ -152,13 +160,59 @@ pub async fn parse(token: &str) -> Result<ClientInfo> {
     let _inner = general_purpose::STANDARD.decode(msg)?;
     let inner = goval::ReplToken::decode(_inner.as_slice())?;
 
-    match inner.presenced {
-        Some(user) => Ok(ClientInfo {
+    let workspace = match inner.metadata {
+        Some(goval::repl_token::Metadata::Repl(repl)) => Some(repl.id),
+        Some(goval::repl_token::Metadata::Id(id)) => Some(id.id),
+        Some(goval::repl_token::Metadata::Classroom(classroom)) => Some(classroom.id),
+        None => None,
+    }
+    .filter(|id| !id.is_empty());
+
+    let client = match inner.presenced {
+        Some(user) => ClientInfo {
             is_secure,
 
             username: user.bearer_name,
             id: user.bearer_id,
-        }),
-        None => Ok(ClientInfo::default()),
+        },
+        None => ClientInfo::default(),
+    };
+
+    Ok(ParsedToken {
+        client,
+        workspace,
+        is_secure,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Signed with an all zero signature, so it can never be verified
+    fn unsigned_token(workspace: &str) -> String {
+        let inner = goval::ReplToken {
+            metadata: Some(goval::repl_token::Metadata::Repl(goval::repl::Repl {
+                id: workspace.to_string(),
+                ..Default::default()
+            })),
+            ..Default::default()
+        };
+        let mut payload = general_purpose::STANDARD
+            .encode(inner.encode_to_vec())
+            .into_bytes();
+        payload.extend([0; 64]);
+
+        format!(
+            "v2.public.{}",
+            general_purpose::URL_SAFE_NO_PAD.encode(payload)
+        )
+    }
+
+    #[tokio::test]
+    async fn unsigned_token_is_not_secure() {
+        let parsed = parse(&unsigned_token("repl")).await.unwrap();
+        assert_eq!(parsed.workspace.as_deref(), Some("repl"));
+        assert!(!parsed.is_secure);
     }
 }
@@ -10,7 +10,7 @@ use axum::{
 use serde::{Deserialize, Serialize};
 use textnonce::TextNonce;
 use tokio::sync::mpsc::channel;
-use tracing::{debug, error, info};
+use tracing::{debug, error, info, warn};
 
 use crate::{ChannelMessage, ReplspaceMessage};
 
@@ -131,6 +131,19 @@ struct OpenFileRes {
 
 async fn open_file(Json(query): Json<OpenFileReq>) -> (StatusCode, Json<OpenFileRes>) {
     debug!("Got git open file");
+    let filename = match homeval_services::paths::resolve(&query.filename) {
+        Ok(resolved) => resolved.relative,
+        Err(err) => {
+            warn!(%err, "Rejected replspace open file request");
+            return (
+                StatusCode::BAD_REQUEST,
+                Json(OpenFileRes {
+                    status: ReplspaceStatus::Err,
+                }),
+            );
+        }
+    };
+
     let session;
     if let Some(channel) = query.channel {
         if channel != 0 {
@@ -161,7 +174,7 @@ async fn open_file(Json(query): Json<OpenFileReq>) -> (StatusCode, Json<OpenFile
 
     let to_send = ChannelMessage::Replspace(
         session,
-        ReplspaceMessage::OpenFileReq(query.filename, query.wait_for_close, nonce),
+        ReplspaceMessage::OpenFileReq(filename, query.wait_for_close, nonce),
         tx,
     );
 